@@ -0,0 +1,150 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::db::EvictionPolicy;
+use crate::log::{Durability, LogFormat};
+use crate::Res;
+
+/// The settings the server runs with.
+///
+/// Read from a TOML file whose keys match the command-line flags, e.g.
+/// `data-dir = "/var/lib/memson"`; flags given on the command line win.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub data_dir: PathBuf,
+    pub db_name: String,
+    pub durability: Durability,
+    // unset keeps the format recorded in the catalog
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_format: Option<LogFormat>,
+    // 0 loads with one thread per CPU
+    pub load_threads: usize,
+    // accept connections before every table is loaded
+    pub load_in_background: bool,
+    // leave tables on disk until they are first used
+    pub lazy_tables: bool,
+    // bytes the tables in memory may take, 0 for no limit
+    pub table_memory: usize,
+    // bytes keys and tables may take together, 0 for no limit
+    pub maxmemory: usize,
+    pub maxmemory_policy: EvictionPolicy,
+    // where client imports are read from, relative to the data directory
+    pub import_dir: PathBuf,
+    // where client exports are written, relative to the data directory
+    pub export_dir: PathBuf,
+    // where client backups are written, relative to the data directory
+    pub backup_dir: PathBuf,
+    // the file holding the key every database file is encrypted with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encryption_key: Option<PathBuf>,
+}
+
+/// The keys that can be set from both the config file and the command line.
+pub const KEYS: &[&str] = &["host", "port", "data-dir", "db-name", "durability", "log-format", "load-threads", "table-memory", "maxmemory", "maxmemory-policy", "encryption-key", "import-dir", "export-dir", "backup-dir"];
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: "127.0.0.1".to_string(),
+            port: 8000,
+            data_dir: PathBuf::from("./"),
+            db_name: "db".to_string(),
+            durability: Durability::EverySecond,
+            log_format: None,
+            load_threads: 0,
+            load_in_background: false,
+            lazy_tables: false,
+            table_memory: 0,
+            maxmemory: 0,
+            maxmemory_policy: EvictionPolicy::NoEviction,
+            import_dir: PathBuf::from("import"),
+            export_dir: PathBuf::from("export"),
+            backup_dir: PathBuf::from("backups"),
+            encryption_key: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, String> {
+        let text = fs::read_to_string(&path).map_err(|err| format!("cannot read {:?}: {}", path.as_ref(), err))?;
+        Config::parse(&text).map_err(|err| format!("bad config file {:?}: {}", path.as_ref(), err))
+    }
+
+    pub fn parse(text: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// Sets one of the `KEYS`, or a switch such as `lazy-tables`, from its
+    /// command-line form.
+    pub fn set(&mut self, key: &str, val: &str) -> Res<()> {
+        match key {
+            "host" => self.host = val.to_string(),
+            "port" => self.port = val.parse().map_err(|_| "port must be a number from 0 to 65535")?,
+            "data-dir" => self.data_dir = PathBuf::from(val),
+            "db-name" => self.db_name = val.to_string(),
+            "durability" => self.durability = val.parse()?,
+            "log-format" => self.log_format = Some(val.parse()?),
+            "load-threads" => self.load_threads = val.parse().map_err(|_| "load threads must be a number")?,
+            "load-in-background" => self.load_in_background = val.parse().map_err(|_| "must be true or false")?,
+            "lazy-tables" => self.lazy_tables = val.parse().map_err(|_| "must be true or false")?,
+            "table-memory" => self.table_memory = val.parse().map_err(|_| "table memory must be a number of bytes")?,
+            "maxmemory" => self.maxmemory = val.parse().map_err(|_| "maxmemory must be a number of bytes")?,
+            "maxmemory-policy" => self.maxmemory_policy = val.parse()?,
+            "encryption-key" => self.encryption_key = Some(PathBuf::from(val)),
+            "import-dir" => self.import_dir = PathBuf::from(val),
+            "export-dir" => self.export_dir = PathBuf::from(val),
+            "backup-dir" => self.backup_dir = PathBuf::from(val),
+            _ => return Err("unknown config key"),
+        }
+        Ok(())
+    }
+
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /// The settings as they would be written in a config file.
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_load() {
+        let config = Config::parse("port = 9000\ndata-dir = \"/var/lib/memson\"\ndurability = \"always\"\n").unwrap();
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.data_dir, PathBuf::from("/var/lib/memson"));
+        assert_eq!(config.durability, Durability::Always);
+        assert_eq!(config.host, "127.0.0.1");
+        assert!(Config::parse("prot = 9000").is_err());
+
+        let mut config = config;
+        config.set("port", "9001").unwrap();
+        config.set("log-format", "bincode").unwrap();
+        config.set("load-in-background", "true").unwrap();
+        config.set("table-memory", "1048576").unwrap();
+        config.set("maxmemory-policy", "allkeys-lfu").unwrap();
+        config.set("encryption-key", "/etc/memson/key").unwrap();
+        config.set("import-dir", "incoming").unwrap();
+        assert_eq!(config.addr(), "127.0.0.1:9001");
+        assert_eq!(config.log_format, Some(LogFormat::Bincode));
+        assert!(config.load_in_background);
+        assert_eq!(config.table_memory, 1 << 20);
+        assert_eq!(config.maxmemory_policy, EvictionPolicy::AllKeysLfu);
+        assert_eq!(config.encryption_key, Some(PathBuf::from("/etc/memson/key")));
+        assert_eq!(config.import_dir, PathBuf::from("incoming"));
+        assert!(config.set("maxmemory-policy", "lru").is_err());
+        assert!(config.set("port", "http").is_err());
+        assert_eq!(Config::parse(&config.to_toml()).unwrap(), config);
+    }
+}
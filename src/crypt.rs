@@ -0,0 +1,144 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+
+use crate::Res;
+
+/// Bytes of the random nonce each sealed record starts with.
+const NONCE_LEN: usize = 24;
+
+/// Bytes of the tag that authenticates each sealed record.
+const TAG_LEN: usize = 16;
+
+/// Bytes a key check takes in the header of an encrypted file.
+pub const CHECK_LEN: usize = NONCE_LEN + TAG_LEN;
+
+/// Bytes of the random id each encrypted file is given when written.
+pub const FILE_ID_LEN: usize = 16;
+
+/// What the key check in a file header seals, along with the file's id, so
+/// a wrong key is caught before any record is read.
+const CHECK_AAD: &[u8] = b"memson key check";
+
+pub const ENCRYPTED: &str = "file is encrypted; start memson with --encryption-key";
+
+pub const WRONG_KEY: &str = "wrong encryption key";
+
+/// The key files are encrypted with, using XChaCha20-Poly1305.
+///
+/// Every record is sealed on its own under a random nonce, so records can
+/// still be appended to a log one at a time. What a record is bound to, such
+/// as its file and position, is passed as associated data.
+#[derive(Clone)]
+pub struct Cipher(Arc<XChaCha20Poly1305>);
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the key
+        write!(f, "Cipher")
+    }
+}
+
+impl Cipher {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Cipher(Arc::new(XChaCha20Poly1305::new(Key::from_slice(key))))
+    }
+
+    /// Reads a key file holding 32 raw bytes or 64 hex digits.
+    pub fn load<P: AsRef<Path>>(path: P) -> Res<Self> {
+        let buf = fs::read(&path).map_err(|err| {
+            eprintln!("cannot read key file {:?}; error = {:?}", path.as_ref(), err);
+            "cannot read encryption key file"
+        })?;
+        let key = match buf.len() {
+            32 => buf,
+            _ => parse_hex(std::str::from_utf8(&buf).unwrap_or("").trim())
+                .ok_or("encryption key file must hold 32 bytes or 64 hex digits")?,
+        };
+        let mut bytes = [0; 32];
+        bytes.copy_from_slice(&key);
+        Ok(Cipher::new(&bytes))
+    }
+
+    /// Encrypts and authenticates a record along with `aad`: the nonce
+    /// followed by the ciphertext and its tag.
+    pub fn seal(&self, msg: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self
+            .0
+            .encrypt(&nonce, Payload { msg, aad })
+            .map_err(|_| io::Error::other("cannot encrypt record"))?;
+        Ok([nonce.as_slice(), &sealed].concat())
+    }
+
+    /// Decrypts a record sealed by `seal` with the same `aad`, or `None` if
+    /// it was sealed with another key or data, or has been tampered with.
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < CHECK_LEN {
+            return None;
+        }
+        let (nonce, msg) = sealed.split_at(NONCE_LEN);
+        self.0.decrypt(XNonce::from_slice(nonce), Payload { msg, aad }).ok()
+    }
+
+    /// A check for the header of the file with `id` that only this key opens.
+    pub fn check(&self, id: &[u8]) -> io::Result<Vec<u8>> {
+        self.seal(&[], &[CHECK_AAD, id].concat())
+    }
+
+    /// Whether `check` was made by `Cipher::check` with this key and `id`.
+    pub fn verify(&self, check: &[u8], id: &[u8]) -> bool {
+        self.open(check, &[CHECK_AAD, id].concat()).is_some()
+    }
+}
+
+/// A random id for a new encrypted file.
+pub fn file_id() -> [u8; FILE_ID_LEN] {
+    let mut id = [0; FILE_ID_LEN];
+    OsRng.fill_bytes(&mut id);
+    id
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() != 64 || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, remove_file};
+
+    use super::*;
+
+    #[test]
+    fn cipher_ok() {
+        fs::write("./crypt_a.key", "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff\n").unwrap();
+        let key = Cipher::load("./crypt_a.key").unwrap();
+        let sealed = key.seal(b"{\"x\":1}", b"at 8").unwrap();
+        assert_eq!(sealed.len(), 7 + CHECK_LEN);
+        assert_eq!(key.open(&sealed, b"at 8"), Some(b"{\"x\":1}".to_vec()));
+        assert_eq!(key.open(&sealed, b"at 9"), None);
+        let id = file_id();
+        assert!(key.verify(&key.check(&id).unwrap(), &id));
+        assert!(!key.verify(&key.check(&id).unwrap(), &file_id()));
+
+        let other = Cipher::new(&[7; 32]);
+        assert_eq!(other.open(&sealed, b"at 8"), None);
+        assert!(!other.verify(&key.check(&id).unwrap(), &id));
+        let mut tampered = sealed.clone();
+        tampered[30] ^= 1;
+        assert_eq!(key.open(&tampered, b"at 8"), None);
+
+        fs::write("./crypt_a.key", "not a key").unwrap();
+        assert!(Cipher::load("./crypt_a.key").is_err());
+        remove_file("./crypt_a.key").unwrap();
+    }
+}
@@ -1,15 +1,22 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::io::{self};
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
 use serde_json::{Value as JsonVal};
 
 use crate::json::*;
+use crate::json::Cmd as JsonCmd;
+use crate::crypt::Cipher;
+use crate::export::{write_file, ExportOptions, ExportSource};
+use crate::import::{read_file, ImportOptions, ImportReport};
 use crate::log::*;
-use crate::query::{Expr, Query};
+use crate::query::{Query, TimeRange};
 
 use crate::Row;
 
@@ -20,151 +27,1675 @@ pub enum Cmd {
     Query(Query),
 }
 
+/// How long a table keeps its rows.
+///
+/// Written as `{"max_rows": 1000}` or
+/// `{"max_age": {"field": "time", "secs": 86400}}`, where the field holds
+/// milliseconds since the unix epoch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Retention {
+    /// Keep only the newest rows, like a ring buffer.
+    #[serde(rename = "max_rows")]
+    MaxRows(usize),
+    /// Drop rows whose timestamp is older than `secs`.
+    #[serde(rename = "max_age")]
+    MaxAge { field: String, secs: u64 },
+}
+
+/// How a table splits its rows into one partition per day.
+///
+/// Written as `{"field": "time"}`, where the field holds milliseconds since
+/// the unix epoch. Each partition is a table of its own, named after the
+/// table and the day, e.g. `ticks.2020-05-20`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Partition {
+    pub field: String,
+}
+
+impl Partition {
+    /// The day the row belongs to, counted from the unix epoch, or `None`
+    /// if it has no timestamp in the partition field.
+    pub fn day_of(&self, row: &Row) -> Option<i64> {
+        let time = row.get(&self.field).and_then(JsonVal::as_f64)?;
+        if time.is_finite() {
+            Some((time / DAY_MILLIS as f64).floor() as i64)
+        } else {
+            None
+        }
+    }
+}
+
+const DAY_MILLIS: i64 = 86_400_000;
+
+/// Formats a day counted from the unix epoch as `YYYY-MM-DD`.
+pub fn format_date(days: i64) -> String {
+    // civil from days, after Howard Hinnant's date algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Parses a `YYYY-MM-DD` date into days since the unix epoch.
+pub fn parse_date(date: &str) -> Option<i64> {
+    let b = date.as_bytes();
+    if b.len() != 10 || b[4] != b'-' || b[7] != b'-' {
+        return None;
+    }
+    let num = |s: &str| if s.bytes().all(|c| c.is_ascii_digit()) { s.parse::<i64>().ok() } else { None };
+    let (year, month, day) = (num(&date[..4])?, num(&date[5..7])?, num(&date[8..])?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    // rejects days past the end of the month, such as 2020-02-30
+    if format_date(days) == date {
+        Some(days)
+    } else {
+        None
+    }
+}
+
+pub const BAD_NAME: &str = "names must be 1 to 64 letters, digits, '_' or '-'";
+
+pub const BAD_PATH: &str = "paths must be relative to their directory and cannot contain '..'";
+
+const BAD_DATE: &str = "dates must be written as YYYY-MM-DD";
+
+const BAD_TABLE_TXN: &str = "tables cannot be renamed, truncated, cloned, snapshotted or given a retention policy in a batch";
+
+const BAD_PARTITION_TXN: &str = "partitions cannot be changed in a batch";
+
+const BAD_PARTITION_CHANGE: &str = "partitioned tables cannot be renamed or cloned";
+
+const NOT_PARTITIONED: &str = "table is not partitioned";
+
+const BAD_PARTITION_RETENTION: &str = "a partitioned table can only keep rows for a max_age of its partition field";
+
+pub const TABLE_LOADING: &str = "table is loading";
+
+pub const OUT_OF_MEMORY: &str = "out of memory: used memory is over maxmemory";
+
+/// Once over `maxmemory`, keys are evicted until memory is down to this
+/// fraction of it, so a full cache does not pick victims on every write.
+const EVICT_TARGET: f64 = 0.95;
+
+/// Which keys are evicted once memory use goes over `maxmemory`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum EvictionPolicy {
+    /// refuse writes that add data
+    #[serde(rename = "noeviction")]
+    NoEviction,
+    /// evict the least recently used keys
+    #[serde(rename = "allkeys-lru")]
+    AllKeysLru,
+    /// evict the least frequently used keys
+    #[serde(rename = "allkeys-lfu")]
+    AllKeysLfu,
+    /// evict the keys with a ttl that expire soonest
+    #[serde(rename = "volatile-ttl")]
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    fn name(&self) -> &'static str {
+        match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::AllKeysLfu => "allkeys-lfu",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+}
+
+impl std::str::FromStr for EvictionPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+            "allkeys-lfu" => Ok(EvictionPolicy::AllKeysLfu),
+            "volatile-ttl" => Ok(EvictionPolicy::VolatileTtl),
+            _ => Err("eviction policy must be one of noeviction, allkeys-lru, allkeys-lfu or volatile-ttl"),
+        }
+    }
+}
+
+/// Snapshot once at least this many rows were logged since the last one...
+const SNAPSHOT_MIN_ROWS: usize = 10_000;
+
+/// ...and the log holds at least this many rows for every row in the snapshot.
+const SNAPSHOT_RATIO: f64 = 1.0;
+
 #[derive(Debug)]
 pub struct Table {
     name: String,
     rows: Vec<Row>,
     log: ReplayLog,
+    retention: Option<Retention>,
+    // rows dropped since the last snapshot
+    pruned: usize,
+    snapshot_id: u64,
+    // rows in the last snapshot, and logged since it was taken
+    snapshot_rows: usize,
+    log_rows: usize,
+    // approximate bytes the rows take in memory
+    size: usize,
+    // when the table was last used, for evicting the coldest tables
+    used: u64,
 }
 
 impl Table {
-    pub fn new<S: Into<String>, P: AsRef<Path>>(name: S, path: P, rows: Vec<Row>) -> io::Result<Self> {
+    /// Creates the table, logging its first rows as the operation `seq`.
+    pub fn new<S: Into<String>, P: AsRef<Path>>(
+        name: S,
+        path: P,
+        format: LogFormat,
+        key: Option<&Cipher>,
+        seq: u64,
+        rows: Vec<Row>,
+    ) -> io::Result<Self> {
         let mut path_buf = PathBuf::new();
         path_buf.push(path);
         let name = name.into();
+        if !is_valid_table_name(&name) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, BAD_NAME));
+        }
         path_buf.push(name.clone() + ".table");
-        let log = ReplayLog::new(path_buf, &rows)?;
-        Ok(Table {
-            name: name.into(),
-            rows,
-            log,
-        })
+        let mut log = ReplayLog::new(path_buf, format, key, &[])?;
+        if !rows.is_empty() {
+            log.append(seq, &rows)?;
+        }
+        let table = Table::from(name, rows, log);
+        if table.snapshot_path().exists() {
+            fs::remove_file(table.snapshot_path())?;
+        }
+        Ok(table)
     }
 
-    pub fn open<S: Into<String>, P: AsRef<Path>>(name: S, path: P) -> Res<Self> {
-        let mut log = ReplayLog::open(path).map_err(|_| "cannot open replay log")?;
-        let rows = log.replay()?;
-        Ok(Table {
-            name: name.into(),
-            rows,
-            log,
-        })
+    /// Loads the table from its latest snapshot plus the rows logged since.
+    pub fn open<S: Into<String>, P: AsRef<Path>>(name: S, path: P, key: Option<&Cipher>) -> Res<Self> {
+        let mut log = ReplayLog::open(path, key).map_err(|_| "cannot open replay log")?;
+        let tail = log.replay()?;
+        let (snapshot_id, mut rows) = read_snapshot(log.path().with_extension("snapshot"), key)?.unwrap_or((0, Vec::new()));
+        let snapshot_rows = rows.len();
+        let checkpoint = log.checkpoint().unwrap_or(0);
+        let log_rows = if checkpoint == snapshot_id {
+            let n = tail.len();
+            rows.extend(tail);
+            n
+        } else if checkpoint < snapshot_id {
+            // the snapshot was written but the log not yet truncated, so the
+            // snapshot already holds every row in the log
+            log.truncate(snapshot_id).map_err(|_| "cannot truncate replay log")?;
+            0
+        } else {
+            return Err("table snapshot is missing");
+        };
+        let mut table = Table::from(name, rows, log);
+        table.snapshot_id = snapshot_id;
+        table.snapshot_rows = snapshot_rows;
+        table.log_rows = log_rows;
+        Ok(table)
     }
 
     pub fn from<S: Into<String>>(name: S, rows: Vec<Row>, log: ReplayLog) -> Self {
+        let log_rows = rows.len();
+        let size = rows.iter().map(row_size).sum();
         Self {
             name: name.into(),
             rows,
             log,
+            retention: None,
+            pruned: 0,
+            snapshot_id: 0,
+            snapshot_rows: 0,
+            log_rows,
+            size,
+            used: 0,
         }
     }
 
-    pub fn insert(&mut self, rows: Vec<Row>) -> io::Result<()> {
-        self.log.insert(&rows)?;
-        self.rows.extend(rows);
+    pub fn insert(&mut self, seq: u64, rows: Vec<Row>) -> io::Result<()> {
+        self.log.append(seq, &rows)?;
+        self.log_rows += rows.len();
+        self.push(rows);
+        self.prune();
+        self.maybe_snapshot()
+    }
+
+    pub fn retention(&self) -> Option<&Retention> {
+        self.retention.as_ref()
+    }
+
+    /// Sets the retention policy and applies it to the rows in memory.
+    pub fn set_retention(&mut self, retention: Option<Retention>) {
+        self.retention = retention;
+        self.prune();
+    }
+
+    /// Drops the rows the retention policy no longer keeps, returning how many
+    /// were dropped. The log still holds them until the next snapshot.
+    pub fn prune(&mut self) -> usize {
+        let before = self.rows.len();
+        match self.retention {
+            Some(Retention::MaxRows(max)) if self.rows.len() > max => {
+                let n = self.rows.len() - max;
+                self.size -= self.rows.drain(..n).map(|row| row_size(&row)).sum::<usize>();
+            }
+            Some(Retention::MaxAge { ref field, secs }) => {
+                let cutoff = now_millis().saturating_sub(secs.saturating_mul(1000)) as f64;
+                let mut dropped = 0;
+                self.rows.retain(|row| match row.get(field).and_then(JsonVal::as_f64) {
+                    Some(time) if time < cutoff => {
+                        dropped += row_size(row);
+                        false
+                    }
+                    _ => true,
+                });
+                self.size -= dropped;
+            }
+            _ => {}
+        }
+        let n = before - self.rows.len();
+        self.pruned += n;
+        n
+    }
+
+    /// Writes the rows to a snapshot file and truncates the log.
+    ///
+    /// The snapshot is in place before the log is truncated; `open` detects a
+    /// crash in between by the log's checkpoint lagging the snapshot.
+    pub fn snapshot(&mut self) -> io::Result<()> {
+        let id = self.snapshot_id + 1;
+        write_snapshot(self.snapshot_path(), self.log.format(), self.log.key(), id, &self.rows)?;
+        self.log.truncate(id)?;
+        self.snapshot_id = id;
+        self.snapshot_rows = self.rows.len();
+        self.log_rows = 0;
+        self.pruned = 0;
+        Ok(())
+    }
+
+    /// Snapshots once the log holds more pruned rows than live ones, or has
+    /// grown large relative to the last snapshot.
+    fn maybe_snapshot(&mut self) -> io::Result<()> {
+        let pruned = self.pruned > 0 && self.pruned >= self.rows.len();
+        let grown = self.log_rows >= SNAPSHOT_MIN_ROWS
+            && self.log_rows as f64 >= self.snapshot_rows as f64 * SNAPSHOT_RATIO;
+        if pruned || grown {
+            self.snapshot()?;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.log.sync()
+    }
+
+    /// Rewrites the log, and the snapshot if there is one, in `format`.
+    pub fn convert(&mut self, format: LogFormat) -> io::Result<()> {
+        self.log.convert(format)?;
+        let bad_snapshot = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+        if let Some((id, rows)) = read_snapshot(self.snapshot_path(), self.log.key()).map_err(bad_snapshot)? {
+            write_snapshot(self.snapshot_path(), format, self.log.key(), id, &rows)?;
+        }
+        Ok(())
+    }
+
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.log.path().with_extension("snapshot")
+    }
+
+    /// Appends rows in memory only; they must be logged with `log_from`.
+    fn push(&mut self, rows: Vec<Row>) {
+        self.size += rows.iter().map(row_size).sum::<usize>();
+        self.rows.extend(rows);
+    }
+
+    /// Drops every row from `len` onwards, in memory only.
+    fn truncate_rows(&mut self, len: usize) {
+        if len < self.rows.len() {
+            self.size -= self.rows.drain(len..).map(|row| row_size(&row)).sum::<usize>();
+        }
+    }
+
+    /// Logs every row from `index` onwards as the operation `seq`.
+    fn log_from(&mut self, seq: u64, index: usize) -> io::Result<()> {
+        self.log.append(seq, &self.rows[index..])?;
+        self.log_rows += self.rows.len() - index;
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn rows(&self) -> &[Row] {
+        &self.rows
+    }
+
+    /// Roughly how many bytes the rows take in memory.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Flushes the log and opens the table's files, to be read for a backup.
+    fn pin(&self) -> io::Result<PinnedTable> {
+        self.log.flush()?;
+        PinnedTable::open(self.name.clone(), self.log.path().to_path_buf(), self.retention.clone(), self.log.key().cloned())
+    }
+
+    /// Drops the table from memory, leaving what is needed to load it again
+    /// from disk. The log must be synced first.
+    fn into_source(self) -> TableSource {
+        let key = self.log.key().cloned();
+        TableSource::new(self.name, self.log.path().to_path_buf(), self.retention, key)
+    }
+}
+
+/// Joins a client's path onto `dir`, refusing one that could leave it.
+fn confine(dir: &Path, path: &str) -> Res<PathBuf> {
+    if is_confined_path(path) {
+        Ok(dir.join(path))
+    } else {
+        Err(BAD_PATH)
+    }
+}
+
+/// Roughly how many bytes a JSON value takes in memory.
+pub fn json_size(val: &JsonVal) -> usize {
+    std::mem::size_of::<JsonVal>()
+        + match val {
+            JsonVal::String(s) => s.len(),
+            JsonVal::Array(arr) => arr.iter().map(json_size).sum(),
+            JsonVal::Object(obj) => row_size(obj),
+            _ => 0,
+        }
+}
+
+fn row_size(row: &Row) -> usize {
+    row.iter().map(|(key, val)| key.len() + json_size(val)).sum()
+}
+
+// Type wrapper
+pub type Cache = BTreeMap<String, Table>;
+
+/// A value in the key/value namespace.
+///
+/// The version is the database write sequence at the time the value was set,
+/// so it only ever increases, even if the key is deleted and set again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    val: JsonVal,
+    version: u64,
+    // milliseconds since the unix epoch
+    expires: Option<u64>,
+    access: Access,
+}
+
+/// When a key was last read and how often, for eviction. Updated through a
+/// shared reference, as reads only hold the read lock.
+#[derive(Debug, Default)]
+struct Access {
+    // milliseconds since the unix epoch
+    last: AtomicU64,
+    hits: AtomicU64,
+}
+
+impl Access {
+    fn new(now: u64) -> Self {
+        Access { last: AtomicU64::new(now), hits: AtomicU64::new(0) }
+    }
+
+    fn hit(&self, now: u64) {
+        self.last.store(now, Ordering::Relaxed);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> u64 {
+        self.last.load(Ordering::Relaxed)
+    }
+
+    fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+impl Clone for Access {
+    fn clone(&self) -> Self {
+        Access { last: AtomicU64::new(self.last()), hits: AtomicU64::new(self.hits()) }
+    }
+}
+
+// two entries are the same whatever their access statistics
+impl PartialEq for Access {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Entry {
+    pub fn val(&self) -> &JsonVal {
+        &self.val
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn expires(&self) -> Option<u64> {
+        self.expires
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|at| at <= now)
+    }
+
+    /// Roughly how many bytes the entry takes in memory under `key`.
+    fn size(&self, key: &str) -> usize {
+        std::mem::size_of::<Entry>() + key.len() + json_size(&self.val)
+    }
+}
+
+/// Milliseconds since the unix epoch.
+/// When a key given `ttl` from now expires, in milliseconds since the unix
+/// epoch; a ttl too long to count never wraps around to an early expiry.
+fn expiry(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis().min(u64::MAX as u128) as u64)
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// The changes made by an in-flight batch.
+///
+/// Nothing is logged until the batch commits; on failure the undo entries
+/// restore the in-memory state.
+#[derive(Debug, Default)]
+struct Txn {
+    ops: Vec<KeyOp>,
+    undo: Vec<(String, Option<Entry>)>,
+    // table name and its row count before the batch touched it
+    tables: Vec<(String, usize)>,
+    created: Vec<String>,
+}
+
+/// The in-memory database shared amongst all clients.
+///
+/// This database will be shared via `Arc`, so to mutate the internal map we're
+/// going to use a `Mutex` for interior mutability.
+#[derive(Debug)]
+pub struct Database {
+    root_path: PathBuf,
+    // where `import` reads files from
+    import_dir: PathBuf,
+    // where `export` writes files to
+    export_dir: PathBuf,
+    // where `backup` writes backups to
+    backup_dir: PathBuf,
+    tables: Vec<Table>,
+    log: DbConfig,
+    // how each partitioned table splits its rows
+    partitions: BTreeMap<String, Partition>,
+    keys: BTreeMap<String, Entry>,
+    // expiry time and key of every key with a ttl, soonest first
+    expiries: BTreeSet<(u64, String)>,
+    key_log: KeyLog,
+    // sequence number of the last write to each key, used by `watch`
+    touched: BTreeMap<String, u64>,
+    seq: u64,
+    txn: Option<Txn>,
+    durability: Durability,
+    // tables listed in the catalog but not loaded yet
+    loading: BTreeSet<String>,
+    // tables left on disk until they are next used
+    cold: BTreeMap<String, TableSource>,
+    // bytes the tables in memory may take before the coldest are evicted,
+    // 0 for no limit
+    table_budget: usize,
+    // counts table uses, to find the least recently used
+    clock: u64,
+    // approximate bytes the keys take in memory
+    key_memory: usize,
+    // bytes keys and tables may take, 0 for no limit
+    max_memory: usize,
+    eviction: EvictionPolicy,
+    evicted_keys: u64,
+    // count of write commands applied, the ticket each waits on to commit
+    writes: u64,
+    commits: Arc<GroupCommit>,
+}
+
+impl Database {
+    pub fn open<P: AsRef<Path>, S: Into<String>>(path: P, name: S) -> Res<Database> {
+        Database::open_with(path, name, 0, None)
+    }
+
+    /// Opens the database, loading its tables on `threads` threads, or one
+    /// per CPU if 0. With `key` every file is encrypted.
+    pub fn open_with<P: AsRef<Path>, S: Into<String>>(path: P, name: S, threads: usize, key: Option<&Cipher>) -> Res<Database> {
+        let (db, sources) = Database::open_catalog(path, name, key)?;
+        let db = Mutex::new(db);
+        load_tables(sources, threads, |table| db.lock().unwrap().loaded(table))?;
+        Ok(db.into_inner().unwrap())
+    }
+
+    /// Opens the database with its keys but none of its tables. Until each
+    /// table is passed to `loaded`, commands on it fail with `TABLE_LOADING`.
+    pub fn open_deferred<P: AsRef<Path>, S: Into<String>>(path: P, name: S, key: Option<&Cipher>) -> Res<(Database, Vec<TableSource>)> {
+        let (mut db, sources) = Database::open_catalog(path, name, key)?;
+        // writes accepted before the tables load must be numbered after them
+        for source in &sources {
+            if let Some(stamp) = source.last_stamp() {
+                db.seq = db.seq.max(stamp.seq);
+            }
+        }
+        Ok((db, sources))
+    }
+
+    fn open_catalog<P: AsRef<Path>, S: Into<String>>(path: P, name: S, key: Option<&Cipher>) -> Res<(Database, Vec<TableSource>)> {
+        let mut root_path = PathBuf::new();
+        root_path.push(path);
+        let name = name.into();
+        if !is_valid_name(&name) {
+            return Err(BAD_NAME);
+        }
+        let mut log = DbConfig::open(&root_path, name.clone(), key).map_err(|_| "cannot open db config file")?;
+        let sources = log.open_tables()?;
+        let partitions = log.partitions();
+        let mut key_path = root_path.clone();
+        key_path.push(name + ".keys");
+        let mut key_log = KeyLog::open(key_path, log.format(), key).map_err(|_| "cannot open key log")?;
+        let mut keys = BTreeMap::new();
+        let mut seq = 0;
+        let interrupted = key_log.replay(|op| replay_key(&mut keys, &mut seq, op))?;
+        if let Some(stamp) = key_log.stamp() {
+            seq = seq.max(stamp.seq);
+        }
+        let sources = match interrupted {
+            Some(batch) => {
+                let sources = log.undo_batch(&batch, sources)?;
+                key_log.abort(batch.seq).map_err(|_| "cannot write key log")?;
+                // the undone batch's number is not handed out again
+                seq = seq.max(batch.seq);
+                sources
+            }
+            None => sources,
+        };
+        let now = now_millis();
+        keys.retain(|_, entry| !entry.is_expired(now));
+        let expiries = keys
+            .iter()
+            .filter_map(|(key, entry)| entry.expires.map(|at| (at, key.clone())))
+            .collect();
+        let loading = sources.iter().map(|source| source.name.clone()).collect();
+        let key_memory = keys.iter().map(|(key, entry)| entry.size(key)).sum();
+        let db = Database {
+            import_dir: root_path.join("import"),
+            export_dir: root_path.join("export"),
+            backup_dir: root_path.join("backups"),
+            root_path,
+            tables: Vec::new(),
+            log,
+            partitions,
+            keys,
+            expiries,
+            key_log,
+            touched: BTreeMap::new(),
+            seq,
+            txn: None,
+            durability: Durability::Os,
+            loading,
+            cold: BTreeMap::new(),
+            table_budget: 0,
+            clock: 0,
+            key_memory,
+            max_memory: 0,
+            eviction: EvictionPolicy::NoEviction,
+            evicted_keys: 0,
+            writes: 0,
+            commits: Arc::new(GroupCommit::new()),
+        };
+        Ok((db, sources))
+    }
+
+    /// Adds a table loaded after `open_deferred`.
+    pub fn loaded(&mut self, table: Table) {
+        // carry on numbering after the last operation in any log
+        if let Some(stamp) = table.log.stamp() {
+            self.seq = self.seq.max(stamp.seq);
+        }
+        self.loading.remove(table.name());
+        self.tables.push(table);
+        if let Err(err) = self.evict_tables() {
+            eprintln!("error evicting tables; error = {:?}", err);
+        }
+    }
+
+    /// Leaves the tables on disk until they are first used.
+    pub fn keep_cold(&mut self, sources: Vec<TableSource>) {
+        for source in sources {
+            self.loading.remove(&source.name);
+            self.cold.insert(source.name.clone(), source);
+        }
+    }
+
+    /// Sets how many bytes the tables in memory may take before the least
+    /// recently used are evicted to disk, 0 for no limit.
+    pub fn set_table_budget(&mut self, bytes: usize) -> Res<()> {
+        self.table_budget = bytes;
+        self.evict_tables()
+    }
+
+    /// Roughly how many bytes the tables in memory take.
+    pub fn table_memory(&self) -> usize {
+        self.tables.iter().map(Table::size).sum()
+    }
+
+    /// Loads the table if it was left on disk and marks it as just used.
+    pub fn warm_table(&mut self, name: &str) -> Res<()> {
+        self.check_loaded(name)?;
+        if let Some(source) = self.cold.get(name) {
+            let table = source.clone().load()?;
+            self.cold.remove(name);
+            self.tables.push(table);
+        }
+        self.use_table(name)
+    }
+
+    /// Marks the table as just used, evicting colder tables if it grew.
+    fn use_table(&mut self, name: &str) -> Res<()> {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(tbl) = self.find_table_mut(name) {
+            tbl.used = clock;
+        }
+        self.evict_tables()
+    }
+
+    /// Evicts the least recently used tables until those in memory fit the
+    /// budget, always keeping the one used last. Nothing is evicted during a
+    /// batch, whose rollback needs the rows in memory.
+    fn evict_tables(&mut self) -> Res<()> {
+        if self.table_budget == 0 || self.txn.is_some() {
+            return Ok(());
+        }
+        let mut size = self.table_memory();
+        while size > self.table_budget && self.tables.len() > 1 {
+            let last = self.tables.iter().enumerate().max_by_key(|(_, t)| t.used).map(|(i, _)| i);
+            let index = match self.tables.iter().enumerate().filter(|(i, _)| Some(*i) != last).min_by_key(|(_, t)| t.used) {
+                Some((index, _)) => index,
+                None => break,
+            };
+            self.tables[index].sync().map_err(|_| "cannot evict table")?;
+            let tbl = self.tables.remove(index);
+            size -= tbl.size();
+            eprintln!("evicted table {} ({} bytes)", tbl.name(), tbl.size());
+            self.cold.insert(tbl.name.clone(), tbl.into_source());
+        }
+        Ok(())
+    }
+
+    fn has_table(&self, name: &str) -> bool {
+        self.find_table(name).is_some() || self.cold.contains_key(name)
+    }
+
+    /// Whether the table is on disk rather than in memory, left there or
+    /// still loading.
+    pub fn is_cold(&self, name: &str) -> bool {
+        self.cold.contains_key(name) || self.loading.contains(name)
+    }
+
+    /// Whether any table is still loading.
+    pub fn is_loading(&self) -> bool {
+        !self.loading.is_empty()
+    }
+
+    /// Fails with `TABLE_LOADING` if the table is not loaded yet.
+    pub fn check_loaded(&self, name: &str) -> Res<()> {
+        if self.loading.contains(name) {
+            Err(TABLE_LOADING)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn insert(&mut self, table: Table) -> io::Result<()> {
+        self.log.insert(table.name())?;
+        self.tables.push(table);
+        Ok(())
+    }
+
+    /// Drops a table, and every partition of it if it is partitioned.
+    pub fn delete_table(&mut self, tbl_name: &str) -> io::Result<bool> {
+        if self.loading.contains(tbl_name) {
+            return Err(io::Error::other(TABLE_LOADING));
+        }
+        if self.partitions.contains_key(tbl_name) {
+            for (part, _) in self.partition_days(tbl_name) {
+                self.delete_table(&part)?;
+            }
+            self.partitions.remove(tbl_name);
+        }
+        if self.cold.remove(tbl_name).is_some() {
+            self.log.remove_table(tbl_name)?;
+            return Ok(true);
+        }
+        match self.table_exits(tbl_name) {
+            Some(index) => {
+                self.tables.remove(index);
+                self.log.remove_table(tbl_name)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn eval_cmd(&mut self, cmd: Cmd) -> Res<()> {
+        match cmd {
+            Cmd::Insert(name, rows) => {
+                self.insert_table(name, rows)?;
+                Ok(())
+            }
+            Cmd::Delete(name) => {
+                self.delete_table(&name).map_err(|_| "cannot delete table")?;
+                Ok(())
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    pub fn eval<S: Into<String>>(&mut self, line: S) -> Res<JsonVal> {
+        let cmd = parse_json_str(line)?;
+        self.exec(cmd)
+    }
+
+    /// Evaluates a command and commits its writes, syncing the logs first if
+    /// every write must be durable before it is acknowledged.
+    pub fn exec(&mut self, cmd: JsonCmd) -> Res<JsonVal> {
+        let (val, _) = self.apply(cmd)?;
+        self.commit_logs().map_err(|_| "cannot sync logs")?;
+        Ok(val)
+    }
+
+    /// Evaluates a command, leaving its writes buffered in the logs. Returns
+    /// the ticket to pass to the group commit before acknowledging it.
+    pub fn apply(&mut self, cmd: JsonCmd) -> Res<(JsonVal, u64)> {
+        if cmd.may_grow() {
+            self.make_room()?;
+        }
+        let val = eval_json_cmd(cmd, self)?;
+        self.writes += 1;
+        Ok((val, self.writes))
+    }
+
+    /// Batches commits across the writers sharing this database.
+    pub fn commits(&self) -> Arc<GroupCommit> {
+        self.commits.clone()
+    }
+
+    /// Flushes every buffered write, syncing too if writes must be durable,
+    /// and returns the last ticket covered. Only needs a shared lock, so
+    /// writers queued behind a commit do not block readers.
+    pub fn commit_logs(&self) -> io::Result<u64> {
+        match self.durability {
+            Durability::Always => self.sync()?,
+            _ => self.flush()?,
+        }
+        Ok(self.writes)
+    }
+
+    /// Hands every buffered write to the OS without waiting for the disk.
+    pub fn flush(&self) -> io::Result<()> {
+        self.key_log.flush()?;
+        for tbl in &self.tables {
+            tbl.log.flush()?;
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// The format new table and key logs are written in.
+    pub fn log_format(&self) -> LogFormat {
+        self.log.format()
+    }
+
+    /// Sets the format new logs are written in, recording it in the catalog.
+    /// Existing logs keep their format until `convert_logs`.
+    pub fn set_log_format(&mut self, format: LogFormat) -> io::Result<()> {
+        if format != self.log.format() {
+            self.log.set_format(format)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the key log and every table log in the database's format.
+    pub fn convert_logs(&mut self) -> io::Result<()> {
+        let format = self.log.format();
+        self.key_log.convert(format)?;
+        for tbl in self.tables.iter_mut() {
+            tbl.convert(format)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    /// Forces every write made so far to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        self.key_log.sync()?;
+        for tbl in &self.tables {
+            tbl.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&JsonVal> {
+        self.get_entry(key).map(Entry::val)
+    }
+
+    /// Looks up a key, treating keys past their expiry as absent.
+    pub fn get_entry(&self, key: &str) -> Option<&Entry> {
+        let now = now_millis();
+        let entry = self.keys.get(key).filter(|entry| !entry.is_expired(now));
+        if let Some(entry) = entry {
+            entry.access.hit(now);
+        }
+        entry
+    }
+
+    pub fn set<S: Into<String>>(&mut self, key: S, val: JsonVal) -> io::Result<Option<JsonVal>> {
+        self.set_ex(key, val, None)
+    }
+
+    /// Sets the key, expiring it after `ttl` if one is given.
+    pub fn set_ex<S: Into<String>>(&mut self, key: S, val: JsonVal, ttl: Option<Duration>) -> io::Result<Option<JsonVal>> {
+        let key = key.into();
+        self.purge(&key);
+        let version = self.seq + 1;
+        let expires = ttl.map(expiry);
+        let mut ops = vec![KeyOp::Set(key.clone(), val.clone(), version)];
+        if let Some(at) = expires {
+            ops.push(KeyOp::Expire(key.clone(), at));
+        }
+        self.write_keys(ops)?;
+        let access = Access::new(now_millis());
+        let prev = self.replace(key.clone(), Some(Entry { val, version, expires, access }));
+        self.touch(key, &prev);
+        Ok(prev.map(|e| e.val))
+    }
+
+    /// Sets the key only if its current version is `expected`, where `None`
+    /// means the key must be absent. Returns the new version.
+    pub fn cas<S: Into<String>>(&mut self, key: S, expected: Option<u64>, val: JsonVal) -> Res<u64> {
+        let key = key.into();
+        self.purge(&key);
+        if self.keys.get(&key).map(Entry::version) != expected {
+            return Err("version conflict");
+        }
+        self.set(key, val).map_err(|_| "cannot write key")?;
+        Ok(self.seq)
+    }
+
+    pub fn del(&mut self, key: &str) -> io::Result<Option<JsonVal>> {
+        self.purge(key);
+        self.write_keys(vec![KeyOp::Del(key.to_string())])?;
+        let prev = self.replace(key.to_string(), None);
+        self.touch(key.to_string(), &prev);
+        Ok(prev.map(|e| e.val))
+    }
+
+    /// Expires the key after `ttl`. Returns false if the key does not exist.
+    pub fn expire(&mut self, key: &str, ttl: Duration) -> io::Result<bool> {
+        self.purge(key);
+        let mut entry = match self.keys.get(key) {
+            Some(entry) => entry.clone(),
+            None => return Ok(false),
+        };
+        let at = expiry(ttl);
+        self.write_keys(vec![KeyOp::Expire(key.to_string(), at)])?;
+        entry.expires = Some(at);
+        let prev = self.replace(key.to_string(), Some(entry));
+        self.touch(key.to_string(), &prev);
+        Ok(true)
+    }
+
+    /// Removes the key's ttl. Returns false if it had none.
+    pub fn persist(&mut self, key: &str) -> io::Result<bool> {
+        self.purge(key);
+        let mut entry = match self.keys.get(key) {
+            Some(entry) if entry.expires.is_some() => entry.clone(),
+            _ => return Ok(false),
+        };
+        self.write_keys(vec![KeyOp::Persist(key.to_string())])?;
+        entry.expires = None;
+        let prev = self.replace(key.to_string(), Some(entry));
+        self.touch(key.to_string(), &prev);
+        Ok(true)
+    }
+
+    /// The time left before the key expires: `None` if the key does not
+    /// exist and `Some(None)` if it never expires.
+    pub fn ttl(&self, key: &str) -> Option<Option<Duration>> {
+        let now = now_millis();
+        self.get_entry(key)
+            .map(|entry| entry.expires.map(|at| Duration::from_millis(at.saturating_sub(now))))
+    }
+
+    /// Drops every key past its expiry, returning how many were dropped.
+    ///
+    /// Nothing is logged: the expiry time is already in the key log, so the
+    /// keys are dropped again on replay.
+    pub fn remove_expired(&mut self) -> usize {
+        let now = now_millis();
+        let expired: Vec<(u64, String)> = self
+            .expiries
+            .iter()
+            .take_while(|(at, _)| *at <= now)
+            .cloned()
+            .collect();
+        for (_, key) in &expired {
+            let prev = self.replace(key.clone(), None);
+            // like a `del`, so a watch on the key sees it go
+            self.touch(key.clone(), &prev);
+        }
+        expired.len()
+    }
+
+    /// Drops the key if it has expired.
+    fn purge(&mut self, key: &str) {
+        if self.keys.get(key).is_some_and(|e| e.is_expired(now_millis())) {
+            self.replace(key.to_string(), None);
+        }
+    }
+
+    /// Replaces the key's entry, keeping the expiry index and memory count
+    /// in step.
+    fn replace(&mut self, key: String, entry: Option<Entry>) -> Option<Entry> {
+        if let Some(at) = self.keys.get(&key).and_then(Entry::expires) {
+            self.expiries.remove(&(at, key.clone()));
+        }
+        if let Some(at) = entry.as_ref().and_then(Entry::expires) {
+            self.expiries.insert((at, key.clone()));
+        }
+        if let Some(ref entry) = entry {
+            self.key_memory += entry.size(&key);
+        }
+        let prev = match entry {
+            Some(entry) => self.keys.insert(key.clone(), entry),
+            None => self.keys.remove(&key),
+        };
+        if let Some(ref prev) = prev {
+            self.key_memory -= prev.size(&key);
+        }
+        prev
+    }
+
+    /// Sets how many bytes keys and tables may take together, 0 for no
+    /// limit, and which keys are evicted to stay under it.
+    pub fn set_max_memory(&mut self, bytes: usize, eviction: EvictionPolicy) {
+        self.max_memory = bytes;
+        self.eviction = eviction;
+    }
+
+    /// Roughly how many bytes keys and tables in memory take.
+    pub fn used_memory(&self) -> usize {
+        self.key_memory + self.table_memory()
+    }
+
+    /// Makes room before a command that may add data. Over `maxmemory`, keys
+    /// are evicted by the eviction policy, and the command is refused if
+    /// that cannot bring memory back under the limit.
+    fn make_room(&mut self) -> Res<()> {
+        if self.max_memory == 0 || self.used_memory() <= self.max_memory {
+            return Ok(());
+        }
+        let mut victims: Vec<(u64, u64, String)> = match self.eviction {
+            EvictionPolicy::NoEviction => Vec::new(),
+            EvictionPolicy::AllKeysLru => {
+                self.keys.iter().map(|(key, e)| (e.access.last(), 0, key.clone())).collect()
+            }
+            EvictionPolicy::AllKeysLfu => {
+                self.keys.iter().map(|(key, e)| (e.access.hits(), e.access.last(), key.clone())).collect()
+            }
+            EvictionPolicy::VolatileTtl => self.expiries.iter().map(|(at, key)| (*at, 0, key.clone())).collect(),
+        };
+        victims.sort_unstable();
+        let target = (self.max_memory as f64 * EVICT_TARGET) as usize;
+        for (_, _, key) in victims {
+            if self.used_memory() <= target {
+                break;
+            }
+            self.del(&key).map_err(|_| "cannot evict key")?;
+            self.evicted_keys += 1;
+        }
+        if self.used_memory() > self.max_memory {
+            return Err(OUT_OF_MEMORY);
+        }
+        Ok(())
+    }
+
+    /// Memory use and eviction counts.
+    pub fn stats(&self) -> JsonVal {
+        serde_json::json!({
+            "used_memory": self.used_memory(),
+            "key_memory": self.key_memory,
+            "table_memory": self.table_memory(),
+            "maxmemory": self.max_memory,
+            "maxmemory_policy": self.eviction.name(),
+            "evicted_keys": self.evicted_keys,
+            "keys": self.keys.len(),
+            "tables": self.tables.len() + self.cold.len() + self.loading.len(),
+            "cold_tables": self.cold.len(),
+        })
+    }
+
+    fn write_keys(&mut self, ops: Vec<KeyOp>) -> io::Result<()> {
+        match self.txn {
+            Some(ref mut txn) => {
+                txn.ops.extend(ops);
+                Ok(())
+            }
+            // the write is numbered like the touch that follows it
+            None => self.key_log.write(self.seq + 1, &ops),
+        }
+    }
+
+    fn touch(&mut self, key: String, prev: &Option<Entry>) {
+        self.seq += 1;
+        if let Some(ref mut txn) = self.txn {
+            txn.undo.push((key.clone(), prev.clone()));
+        }
+        self.touched.insert(key, self.seq);
+    }
+
+    /// The sequence number of the last write to the key, for `watch`.
+    pub fn watch_seq(&self, key: &str) -> u64 {
+        self.touched.get(key).cloned().unwrap_or(0)
+    }
+
+    /// Applies the commands atomically: either every command succeeds and is
+    /// logged, or the database is left as it was.
+    pub fn eval_batch(&mut self, cmds: Vec<JsonCmd>) -> Res<Vec<JsonVal>> {
+        if self.txn.is_some() {
+            // nested batches join the enclosing one
+            return cmds.into_iter().map(|cmd| eval_json_cmd(cmd, self)).collect();
+        }
+        self.txn = Some(Txn::default());
+        let mut vals = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            match eval_json_cmd(cmd, self) {
+                Ok(val) => vals.push(val),
+                Err(err) => {
+                    self.rollback();
+                    return Err(err);
+                }
+            }
+        }
+        if self.commit().is_err() {
+            self.rollback();
+            return Err("cannot commit batch");
+        }
+        Ok(vals)
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        let txn = match self.txn {
+            Some(ref txn) => txn,
+            None => return Ok(()),
+        };
+        // the whole batch is logged as one operation
+        self.seq += 1;
+        if txn.tables.is_empty() {
+            if !txn.ops.is_empty() {
+                self.key_log.write(self.seq, &txn.ops)?;
+            }
+        } else {
+            // the batch spans several files, so it is journaled in the key log
+            // and each file is written out before the next is touched: the
+            // tables, then the catalog entries of those created, then the
+            // commit marker
+            let sync = self.durability == Durability::Always;
+            let batch = Batch {
+                seq: self.seq,
+                tables: txn.tables.iter().map(|(name, _)| name.clone()).collect(),
+                created: txn.created.clone(),
+            };
+            self.key_log.begin(&batch, sync)?;
+            for (name, len) in &txn.tables {
+                if let Some(tbl) = self.tables.iter_mut().find(|t| t.name() == name) {
+                    tbl.log_from(self.seq, *len)?;
+                    if sync {
+                        tbl.log.sync()?;
+                    } else {
+                        tbl.log.flush()?;
+                    }
+                }
+            }
+            for name in &txn.created {
+                self.log.insert(name.as_str())?;
+            }
+            self.key_log.commit(self.seq, &txn.ops, sync)?;
+        }
+        if let Some(txn) = self.txn.take() {
+            // rows are only pruned once the batch can no longer be rolled back
+            for (name, _) in txn.tables {
+                if let Some(tbl) = self.find_table_mut(&name) {
+                    tbl.prune();
+                    tbl.maybe_snapshot()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) {
+        let txn = match self.txn.take() {
+            Some(txn) => txn,
+            None => return,
+        };
+        for (key, prev) in txn.undo.into_iter().rev() {
+            self.replace(key, prev);
+        }
+        for (name, len) in txn.tables {
+            if let Some(tbl) = self.find_table_mut(&name) {
+                tbl.truncate_rows(len);
+            }
+        }
+        for name in txn.created {
+            if let Some(index) = self.table_exits(&name) {
+                self.tables.remove(index);
+                let mut path = self.root_path.clone();
+                path.push(name + ".table");
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    pub fn find_table(&self, name: &str) -> Option<&Table> {
+        self.tables.iter().find(|x| x.name() == name)
+    }
+
+    pub fn find_table_mut(&mut self, name: &str) -> Option<&mut Table> {
+        self.tables.iter_mut().find(|x| x.name() == name)
+    }
+
+    pub fn insert_table(&mut self, name: String, rows: Vec<Row>) -> Res<()> {
+        if !is_valid_name(&name) {
+            return Err(BAD_NAME);
+        }
+        match self.partitions.get(&name).cloned() {
+            Some(partition) => self.insert_partitioned(&name, &partition, rows),
+            None => self.insert_rows(name, rows),
+        }
+    }
+
+    fn insert_rows(&mut self, name: String, rows: Vec<Row>) -> Res<()> {
+        self.warm_table(&name)?;
+        if self.txn.is_some() {
+            return self.stage_rows(name, rows).map_err(|_| "cannot insert");
+        }
+        self.seq += 1;
+        let seq = self.seq;
+        let r = self.find_table_mut(&name);
+        match r {
+            Some(tbl) => {
+                tbl.insert(seq, rows).map_err(|_| "cannot insert")?;
+            }
+            None => {
+                self.log.insert(name.as_str()).map_err(|_| "cannot write db config")?;
+                let tbl = Table::new(name.clone(), self.root_path.clone(), self.log.format(), self.log.key(), seq, rows).map_err(|_| "cannot insert")?;
+                self.tables.push(tbl);
+            }
+        }
+        self.use_table(&name)
+    }
+
+    /// Inserts each row into the partition for its day. Rows spanning
+    /// several partitions are inserted as one batch.
+    fn insert_partitioned(&mut self, name: &str, partition: &Partition, rows: Vec<Row>) -> Res<()> {
+        let mut parts: BTreeMap<String, Vec<Row>> = BTreeMap::new();
+        for row in rows {
+            let day = partition.day_of(&row).ok_or("rows of a partitioned table need a time in the partition field")?;
+            parts.entry(partition_name(name, &format_date(day))).or_default().push(row);
+        }
+        if parts.len() < 2 || self.txn.is_some() {
+            for (part, rows) in parts {
+                self.insert_rows(part, rows)?;
+            }
+            return Ok(());
+        }
+        self.txn = Some(Txn::default());
+        for (part, rows) in parts {
+            if let Err(err) = self.insert_rows(part, rows) {
+                self.rollback();
+                return Err(err);
+            }
+        }
+        if self.commit().is_err() {
+            self.rollback();
+            return Err("cannot insert");
+        }
+        self.evict_tables()
+    }
+
+    /// Partitions an empty table, creating it if need be, by the day of a
+    /// timestamp field.
+    pub fn set_partition(&mut self, name: &str, partition: Partition) -> Res<()> {
+        if self.txn.is_some() {
+            return Err(BAD_PARTITION_TXN);
+        }
+        if !is_valid_name(name) {
+            return Err(BAD_NAME);
+        }
+        if self.partitions.contains_key(name) {
+            return Err("table is already partitioned");
+        }
+        self.warm_table(name)?;
+        match self.find_table(name) {
+            Some(tbl) if tbl.len() > 0 => return Err("only an empty table can be partitioned"),
+            Some(tbl) => match tbl.retention() {
+                Some(Retention::MaxAge { field, .. }) if *field == partition.field => {}
+                Some(_) => return Err(BAD_PARTITION_RETENTION),
+                None => {}
+            },
+            None => {
+                self.log.insert(name).map_err(|_| "cannot write db config")?;
+                let tbl = Table::new(name, self.root_path.clone(), self.log.format(), self.log.key(), 0, Vec::new()).map_err(|_| "cannot create table")?;
+                self.tables.push(tbl);
+            }
+        }
+        self.log
+            .set_partition(name, Some(partition.clone()))
+            .map_err(|_| "cannot write db config")?;
+        self.partitions.insert(name.to_string(), partition);
+        self.use_table(name)
+    }
+
+    /// The partitions of a table and their days, oldest first, whether they
+    /// are in memory, on disk or still loading.
+    pub fn partition_days(&self, name: &str) -> Vec<(String, i64)> {
+        let names = self.tables.iter().map(Table::name).chain(self.cold.keys().map(String::as_str)).chain(self.loading.iter().map(String::as_str));
+        let mut parts: Vec<(String, i64)> = names
+            .filter_map(|part| match split_partition(part) {
+                Some((table, date)) if table == name => Some((part.to_string(), parse_date(date)?)),
+                _ => None,
+            })
+            .collect();
+        parts.sort_by_key(|(_, day)| *day);
+        parts
+    }
+
+    /// The dates of a partitioned table's partitions, oldest first.
+    pub fn partition_dates(&self, name: &str) -> Res<Vec<String>> {
+        if !self.partitions.contains_key(name) {
+            return Err(NOT_PARTITIONED);
+        }
+        Ok(self.partition_days(name).into_iter().map(|(_, day)| format_date(day)).collect())
+    }
+
+    /// The tables a query on `name` reads: the table itself, or the
+    /// partitions of it that a filter on the partition field can match.
+    pub fn scan_tables(&self, name: &str, time: Option<&TimeRange>) -> Vec<String> {
+        let partition = match self.partitions.get(name) {
+            Some(partition) => partition,
+            None => return vec![name.to_string()],
+        };
+        let range = time.filter(|time| time.field == partition.field);
+        self.partition_days(name)
+            .into_iter()
+            .filter(|(_, day)| range.is_none_or(|range| range.overlaps(day * DAY_MILLIS, (day + 1) * DAY_MILLIS)))
+            .map(|(part, _)| part)
+            .collect()
+    }
+
+    /// The partitions of a table dated before `before`, checking the table
+    /// is partitioned and the date valid.
+    fn partitions_before(&self, name: &str, before: &str) -> Res<Vec<String>> {
+        if self.txn.is_some() {
+            return Err(BAD_PARTITION_TXN);
+        }
+        if !self.partitions.contains_key(name) {
+            return Err(NOT_PARTITIONED);
+        }
+        let before = parse_date(before).ok_or(BAD_DATE)?;
+        Ok(self.partition_days(name).into_iter().filter(|(_, day)| *day < before).map(|(part, _)| part).collect())
+    }
+
+    /// Drops the partitions of a table dated before `before`, returning how
+    /// many were dropped.
+    pub fn drop_partitions(&mut self, name: &str, before: &str) -> Res<usize> {
+        let parts = self.partitions_before(name, before)?;
+        for part in &parts {
+            self.delete_table(part).map_err(|_| "cannot drop partition")?;
+        }
+        Ok(parts.len())
+    }
+
+    /// Writes the partitions of a table dated before `before` into `dir` as
+    /// NDJSON files, one per partition, then drops them. Returns how many
+    /// were archived.
+    pub fn archive_partitions<P: AsRef<Path>>(&mut self, name: &str, before: &str, dir: P) -> Res<usize> {
+        let parts = self.partitions_before(name, before)?;
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).map_err(|_| "cannot create archive directory")?;
+        for part in &parts {
+            self.warm_table(part)?;
+            let tbl = self.find_table(part).ok_or("cannot find table")?;
+            write_file(dir.join(part.clone() + ".ndjson"), tbl.rows(), &ExportOptions::default())?;
+            self.delete_table(part).map_err(|_| "cannot drop partition")?;
+        }
+        Ok(parts.len())
+    }
+
+    /// Sets the directory client imports are read from.
+    pub fn set_import_dir<P: AsRef<Path>>(&mut self, dir: P) {
+        self.import_dir = dir.as_ref().to_path_buf();
+    }
+
+    /// Resolves a path given by a client to a file in the import directory.
+    pub fn import_path(&self, path: &str) -> Res<PathBuf> {
+        confine(&self.import_dir, path)
+    }
+
+    /// Sets the directory client exports are written to.
+    pub fn set_export_dir<P: AsRef<Path>>(&mut self, dir: P) {
+        self.export_dir = dir.as_ref().to_path_buf();
+    }
+
+    /// Resolves a path given by a client to a file in the export directory.
+    pub fn export_path(&self, path: &str) -> Res<PathBuf> {
+        confine(&self.export_dir, path)
+    }
+
+    /// Inserts the rows of a CSV or NDJSON file into a table as one operation.
+    pub fn import<P: AsRef<Path>>(&mut self, name: String, path: P, opts: &ImportOptions) -> Res<ImportReport> {
+        let (rows, report) = read_file(path, opts)?;
+        if !rows.is_empty() {
+            self.insert_table(name, rows)?;
+        }
+        Ok(report)
+    }
+
+    /// The rows an export writes out, loading the table first if it is cold.
+    pub fn export_rows(&mut self, source: &ExportSource) -> Res<Vec<Row>> {
+        match source {
+            ExportSource::Table(name) => {
+                let mut rows = Vec::new();
+                for name in self.scan_tables(name, None) {
+                    self.warm_table(&name)?;
+                    rows.extend_from_slice(self.find_table(&name).ok_or("cannot find table")?.rows());
+                }
+                Ok(rows)
+            }
+            ExportSource::Query(qry) => qry.exec(self),
+        }
+    }
+
+    /// Writes a table or query result to a new CSV or NDJSON file, returning
+    /// how many rows were written.
+    pub fn export<P: AsRef<Path>>(&mut self, source: &ExportSource, path: P, opts: &ExportOptions) -> Res<usize> {
+        write_file(path, &self.export_rows(source)?, opts)
+    }
+
+    fn stage_rows(&mut self, name: String, rows: Vec<Row>) -> io::Result<()> {
+        let len = match self.find_table(&name) {
+            Some(tbl) => tbl.len(),
+            None => {
+                let tbl = Table::new(name.clone(), self.root_path.clone(), self.log.format(), self.log.key(), 0, Vec::new())?;
+                self.tables.push(tbl);
+                if let Some(ref mut txn) = self.txn {
+                    txn.created.push(name.clone());
+                }
+                0
+            }
+        };
+        if let Some(ref mut txn) = self.txn {
+            if !txn.tables.iter().any(|(t, _)| *t == name) {
+                txn.tables.push((name.clone(), len));
+            }
+        }
+        if let Some(tbl) = self.find_table_mut(&name) {
+            tbl.push(rows);
+        }
         Ok(())
     }
 
-    pub fn name(&self) -> &str {
-        &self.name
+    /// Sets the table's retention policy, recording it in the catalog.
+    ///
+    /// A partitioned table can only keep rows for a time, by its partition
+    /// field, and drops whole partitions as they expire.
+    pub fn set_retention(&mut self, name: &str, retention: Option<Retention>) -> Res<()> {
+        if self.txn.is_some() {
+            return Err(BAD_TABLE_TXN);
+        }
+        if let Some(partition) = self.partitions.get(name) {
+            match retention {
+                Some(Retention::MaxAge { ref field, .. }) if *field == partition.field => {}
+                Some(_) => return Err(BAD_PARTITION_RETENTION),
+                None => {}
+            }
+        }
+        self.warm_table(name)?;
+        if self.find_table(name).is_none() {
+            return Err("cannot find table");
+        }
+        self.log
+            .set_retention(name, retention.clone())
+            .map_err(|_| "cannot write db config")?;
+        let tbl = self.find_table_mut(name).unwrap();
+        tbl.set_retention(retention);
+        tbl.maybe_snapshot().map_err(|_| "cannot snapshot table")
     }
 
-    pub fn len(&self) -> usize {
-        self.rows.len()
+    /// Applies every table's retention policy and snapshots the tables that
+    /// dropped rows, returning how many rows were dropped. Partitions whose
+    /// whole day has expired are dropped, counting the rows of those in
+    /// memory.
+    pub fn enforce_retention(&mut self) -> io::Result<usize> {
+        let mut dropped = 0;
+        for tbl in self.tables.iter_mut() {
+            dropped += tbl.prune();
+            if tbl.pruned > 0 {
+                tbl.snapshot()?;
+            }
+        }
+        let now = now_millis() as i64;
+        let mut expired = Vec::new();
+        for name in self.partitions.keys() {
+            if let Some(Retention::MaxAge { secs, .. }) = self.find_table(name).and_then(Table::retention) {
+                let cutoff = now.saturating_sub((*secs).min(MAX_SECS) as i64 * 1000);
+                let parts = self.partition_days(name).into_iter().filter(|(_, day)| (day + 1) * DAY_MILLIS <= cutoff);
+                expired.extend(parts.map(|(part, _)| part));
+            }
+        }
+        for part in expired {
+            dropped += self.find_table(&part).map_or(0, Table::len);
+            self.delete_table(&part)?;
+        }
+        Ok(dropped)
     }
 
-    pub fn rows(&self) -> &[Row] {
-        &self.rows
+    /// Snapshots the named table, or every table in memory if no name is
+    /// given. Returns how many tables were snapshotted.
+    pub fn snapshot(&mut self, name: Option<&str>) -> Res<usize> {
+        if self.txn.is_some() {
+            return Err(BAD_TABLE_TXN);
+        }
+        if let Some(name) = name {
+            self.warm_table(name)?;
+        }
+        let mut n = 0;
+        for tbl in self.tables.iter_mut() {
+            if name.is_none_or(|name| name == tbl.name()) {
+                tbl.snapshot().map_err(|_| "cannot snapshot table")?;
+                n += 1;
+            }
+        }
+        if n == 0 && name.is_some() {
+            return Err("cannot find table");
+        }
+        Ok(n)
     }
-}
-
-// Type wrapper
-pub type Cache = BTreeMap<String, Table>;
-
-/// The in-memory database shared amongst all clients.
-///
-/// This database will be shared via `Arc`, so to mutate the internal map we're
-/// going to use a `Mutex` for interior mutability.
-#[derive(Debug)]
-pub struct Database {
-    root_path: PathBuf,
-    tables: Vec<Table>,
-    log: DbConfig,
-}
 
-impl Database {
-    pub fn open<P: AsRef<Path>, S: Into<String>>(path: P, name: S) -> Res<Database> {
-        let mut root_path = PathBuf::new();
-        root_path.push(path);
-        let mut log = DbConfig::open(&root_path, name).map_err(|_| "cannot open db config file")?;
-        let tables = log.load()?;
-        Ok(Database {
-            root_path,
+    /// Takes a backup of the database as of its last operation, to be written
+    /// out with `Backup::write` without holding up writes. The keys are
+    /// copied, but the tables are only flushed and their files opened.
+    pub fn backup_image(&self) -> Res<Backup> {
+        if self.txn.is_some() {
+            return Err("backup cannot be run in a batch");
+        }
+        if self.is_loading() {
+            return Err(TABLE_LOADING);
+        }
+        let mut tables = Vec::with_capacity(self.tables.len() + self.cold.len());
+        for tbl in &self.tables {
+            tables.push(tbl.pin().map_err(|_| "cannot read table for backup")?);
+        }
+        for source in self.cold.values() {
+            tables.push(source.pin().map_err(|_| "cannot read table for backup")?);
+        }
+        let now = now_millis();
+        let mut keys = Vec::with_capacity(self.keys.len());
+        for (key, entry) in self.keys.iter().filter(|(_, entry)| !entry.is_expired(now)) {
+            keys.push(KeyOp::Set(key.clone(), entry.val.clone(), entry.version));
+            if let Some(at) = entry.expires {
+                keys.push(KeyOp::Expire(key.clone(), at));
+            }
+        }
+        Ok(Backup {
+            name: self.log.name().to_string(),
+            format: self.log.format(),
+            seq: self.seq,
             tables,
-            log,
+            partitions: self.partitions.clone(),
+            keys,
+            key: self.log.key().cloned(),
         })
     }
 
-    pub fn insert(&mut self, table: Table) -> io::Result<()> {
-        self.log.insert(table.name())?;
-        self.tables.push(table);
-        Ok(())
+    /// Sets the directory client backups are written to.
+    pub fn set_backup_dir<P: AsRef<Path>>(&mut self, dir: P) {
+        self.backup_dir = dir.as_ref().to_path_buf();
     }
 
-    pub fn delete_table(&mut self, tbl_name: &str) -> io::Result<bool> {
-        self.log.remove_table(tbl_name)?;
-        let found = self.tables.iter().position(|t| t.name() == tbl_name);
-        Ok(match found {
-            Some(index) => {
-                self.tables.remove(index);
-                let mut path = self.root_path.clone();
-                path.push(tbl_name.to_string() + ".table");
-                fs::remove_file(path)?;
-                true
-            }
-            None => false,
-        })
+    /// Resolves a directory given by a client to one in the backup directory.
+    pub fn backup_path(&self, dir: &str) -> Res<PathBuf> {
+        confine(&self.backup_dir, dir)
     }
 
-    pub fn eval_cmd(&mut self, cmd: Cmd) -> Res<()> {
-        match cmd {
-            Cmd::Insert(name, rows) => {
-                self.insert_table(name, rows).map_err(|_| "cannot insert")?;
-                Ok(())
-            }
-            Cmd::Delete(name) => {
-                self.delete_table(&name).map_err(|_| "cannot delete table")?;
-                Ok(())
-            }
-            _ => unimplemented!(),
-        }
+    /// Writes a backup into `dir`, returning the sequence number it holds.
+    pub fn backup<P: AsRef<Path>>(&self, dir: P) -> Res<u64> {
+        self.backup_image()?.write(dir)
     }
 
-    pub fn eval<S: Into<String>>(&mut self, line: S) -> Res<JsonVal> {
-        let line = line.into();
-        unimplemented!()
+    /// Renames a table along with its files.
+    pub fn rename_table(&mut self, from: &str, to: &str) -> Res<()> {
+        self.check_table_change(from, to)?;
+        self.log.rename_table(from, to).map_err(|_| "cannot rename table")?;
+        let mut path = self.root_path.clone();
+        path.push(to.to_string() + ".table");
+        let tbl = self.find_table_mut(from).unwrap();
+        tbl.name = to.to_string();
+        tbl.log.moved(path);
+        Ok(())
     }
 
-    pub fn find_table(&self, name: &str) -> Option<&Table> {
-        self.tables.iter().find(|x| x.name() == name)
+    /// Drops every row of a table, keeping the table and its retention
+    /// policy. Returns how many rows were dropped. A partitioned table drops
+    /// all of its partitions.
+    pub fn truncate_table(&mut self, name: &str) -> Res<usize> {
+        if self.txn.is_some() {
+            return Err(BAD_TABLE_TXN);
+        }
+        if self.partitions.contains_key(name) {
+            let mut n = 0;
+            for (part, _) in self.partition_days(name) {
+                self.warm_table(&part)?;
+                n += self.find_table(&part).map_or(0, Table::len);
+                self.delete_table(&part).map_err(|_| "cannot drop partition")?;
+            }
+            return Ok(n);
+        }
+        self.warm_table(name)?;
+        self.find_table(name).ok_or("cannot find table")?;
+        self.seq += 1;
+        let seq = self.seq;
+        let tbl = self.find_table_mut(name).unwrap();
+        let n = tbl.rows.len();
+        tbl.truncate_rows(0);
+        tbl.log.mark(seq);
+        tbl.snapshot().map_err(|_| "cannot snapshot table")?;
+        Ok(n)
     }
 
-    pub fn find_table_mut(&mut self, name: &str) -> Option<&mut Table> {
-        self.tables.iter_mut().find(|x| x.name() == name)
+    /// Copies a table's rows and retention policy into a new table, returning
+    /// how many rows were copied.
+    pub fn clone_table(&mut self, from: &str, to: &str) -> Res<usize> {
+        self.check_table_change(from, to)?;
+        let src = self.find_table(from).unwrap();
+        let (rows, retention) = (src.rows.clone(), src.retention.clone());
+        let n = rows.len();
+        self.log.insert(to).map_err(|_| "cannot write db config")?;
+        self.seq += 1;
+        let tbl = Table::new(to, self.root_path.clone(), self.log.format(), self.log.key(), self.seq, rows).map_err(|_| "cannot create table")?;
+        self.tables.push(tbl);
+        if retention.is_some() {
+            self.set_retention(to, retention)?;
+        }
+        self.use_table(to)?;
+        Ok(n)
     }
 
-    pub fn insert_table(&mut self, name: String, rows: Vec<Row>) -> io::Result<()> {
-        let r = self.find_table_mut(&name);
-        match r {
-            Some(tbl) => {
-                tbl.insert(rows)
-            }
-            None => {
-                let tbl = Table::new(name, self.root_path.clone(), rows)?;
-                self.tables.push(tbl);
-                Ok(())
-            }
+    /// Checks that `from` can be renamed or cloned to `to`, loading `from` if
+    /// it was left on disk.
+    fn check_table_change(&mut self, from: &str, to: &str) -> Res<()> {
+        if self.txn.is_some() {
+            return Err(BAD_TABLE_TXN);
+        }
+        if self.partitions.contains_key(from) || split_partition(from).is_some() {
+            return Err(BAD_PARTITION_CHANGE);
+        }
+        self.check_loaded(to)?;
+        self.warm_table(from)?;
+        if self.find_table(from).is_none() {
+            return Err("cannot find table");
         }
+        if !is_valid_name(to) {
+            return Err(BAD_NAME);
+        }
+        if self.has_table(to) {
+            return Err("table already exists");
+        }
+        Ok(())
     }
 
     pub fn table_exits(&self, name: &str) -> Option<usize> {
@@ -172,13 +1703,39 @@ impl Database {
     }
 }
 
+fn replay_key(keys: &mut BTreeMap<String, Entry>, seq: &mut u64, op: KeyOp) {
+    match op {
+        KeyOp::Set(key, val, version) => {
+            // records written before keys were versioned carry version 0
+            let version = if version == 0 { *seq + 1 } else { version };
+            *seq = (*seq).max(version);
+            keys.insert(key, Entry { val, version, expires: None, access: Access::default() });
+        }
+        KeyOp::Del(key) => {
+            keys.remove(&key);
+        }
+        KeyOp::Expire(key, at) => {
+            if let Some(entry) = keys.get_mut(&key) {
+                entry.expires = Some(at);
+            }
+        }
+        KeyOp::Persist(key) => {
+            if let Some(entry) = keys.get_mut(&key) {
+                entry.expires = None;
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::remove_file;
 
-    use serde_json::Map;
+    use serde_json::{json, Map};
 
     use super::*;
+    use crate::crypt::{ENCRYPTED, WRONG_KEY};
+    use crate::query::Expr;
     use crate::Row;
     use crate::obj;
 
@@ -271,9 +1828,782 @@ mod tests {
         assert_eq!(tbl.rows[2], obj! {"x" => "s"});
 
         remove_file("./test.db").unwrap();
+
+        remove_file("./test.keys").unwrap();
         remove_file("./t.table").unwrap();
     }
 
+    #[test]
+    fn set_get_ok() {
+        let mut db = Database::open("./", "kv").unwrap();
+        assert_eq!(db.eval("{\"set\": [\"k1\", [1,2,3,4]]}"), Ok(JsonVal::Null));
+        assert_eq!(db_get(&mut db, "k1"), Ok(json!([1, 2, 3, 4])));
+        assert_eq!(eval(&mut db, first(get("k1"))), Ok(json!(1)));
+        assert_eq!(eval(&mut db, "{\"del\": \"k1\"}"), Ok(json!([1, 2, 3, 4])));
+        assert_eq!(db_get(&mut db, "k1"), Ok(JsonVal::Null));
+        assert_eq!(eval(&mut db, "{}"), Err("bad command"));
+        assert_eq!(eval(&mut db, "{\"set\": [\"k1\", 1], \"del\": \"k1\"}"), Err("bad command"));
+        assert_eq!(db_get(&mut db, "k1"), Ok(JsonVal::Null));
+
+        remove_file("./kv.db").unwrap();
+
+        remove_file("./kv.keys").unwrap();
+    }
+
+    #[test]
+    fn batch_ok() {
+        let mut db = Database::open("./", "batch").unwrap();
+        let cmd = parse_batch(vec![
+            json!({"set": ["a", 1]}),
+            json!({"insert": ["batch", [{"x": 1}, {"x": 2}]]}),
+            json!({"get": "a"}),
+        ])
+        .unwrap();
+        assert_eq!(eval_json_cmd(cmd, &mut db), Ok(json!([null, 2, 1])));
+        assert_eq!(db.find_table("batch").unwrap().len(), 2);
+
+        // reopen to check the batch was logged
+        db.flush().unwrap();
+        let db = Database::open("./", "batch").unwrap();
+        assert_eq!(db.get("a"), Some(&json!(1)));
+        assert_eq!(db.find_table("batch").unwrap().len(), 2);
+
+        remove_file("./batch.db").unwrap();
+        remove_file("./batch.keys").unwrap();
+        remove_file("./batch.table").unwrap();
+    }
+
+    #[test]
+    fn batch_interrupted_ok() {
+        let mut db = Database::open("./", "torn").unwrap();
+        db.eval("{\"set\": [\"a\", 1]}").unwrap();
+        db.eval("{\"insert\": [\"torn_a\", {\"x\": 1}]}").unwrap();
+        let cmd = parse_batch(vec![
+            json!({"insert": ["torn_a", {"x": 2}]}),
+            json!({"insert": ["torn_b", {"y": 1}]}),
+            json!({"set": ["a", 2]}),
+        ])
+        .unwrap();
+        eval_json_cmd(cmd, &mut db).unwrap();
+        let seq = db.seq;
+        drop(db);
+
+        // a crash after the tables and catalog were written but before the
+        // commit marker: drop the key log's stamp, op and marker
+        let keys = std::fs::read_to_string("./torn.keys").unwrap();
+        let lines: Vec<&str> = keys.lines().collect();
+        std::fs::write("./torn.keys", lines[..lines.len() - 3].join("\n") + "\n").unwrap();
+        let db = Database::open("./", "torn").unwrap();
+        assert_eq!(db.get("a"), Some(&json!(1)));
+        assert_eq!(db.find_table("torn_a").unwrap().rows(), &[obj! {"x" => 1}]);
+        assert!(db.find_table("torn_b").is_none());
+        assert!(!Path::new("./torn_b.table").exists());
+        assert!(db.seq >= seq);
+        drop(db);
+
+        // the undo is only done once
+        let mut db = Database::open("./", "torn").unwrap();
+        db.eval("{\"insert\": [\"torn_a\", {\"x\": 3}]}").unwrap();
+        drop(db);
+        let db = Database::open("./", "torn").unwrap();
+        assert_eq!(db.find_table("torn_a").unwrap().rows(), &[obj! {"x" => 1}, obj! {"x" => 3}]);
+        assert!(db.find_table("torn_b").is_none());
+
+        remove_file("./torn.db").unwrap();
+        remove_file("./torn.keys").unwrap();
+        remove_file("./torn_a.table").unwrap();
+    }
+
+    #[test]
+    fn batch_rollback_ok() {
+        let mut db = Database::open("./", "rollback").unwrap();
+        db.eval("{\"set\": [\"a\", 1]}").unwrap();
+        let cmd = parse_batch(vec![
+            json!({"set": ["a", 2]}),
+            json!({"insert": ["rollback", {"x": 1}]}),
+            json!({"+": [{"get": "a"}, true]}),
+        ])
+        .unwrap();
+        assert_eq!(eval_json_cmd(cmd, &mut db), Err("bad type"));
+        assert_eq!(db.get("a"), Some(&json!(1)));
+        assert!(db.find_table("rollback").is_none());
+
+        let db = Database::open("./", "rollback").unwrap();
+        assert_eq!(db.get("a"), Some(&json!(1)));
+        assert!(db.find_table("rollback").is_none());
+
+        remove_file("./rollback.db").unwrap();
+        remove_file("./rollback.keys").unwrap();
+    }
+
+    #[test]
+    fn batch_refused_ok() {
+        let mut db = Database::open("./", "lone").unwrap();
+        let cmds = [
+            json!({"backup": "lone"}),
+            json!({"export": ["lone", "./lone.csv"]}),
+            json!({"import": ["lone", "./lone.csv"]}),
+            json!({"query": {"selects": [], "from": "lone"}}),
+        ];
+        // refused whether the rest of the batch writes or only reads
+        for cmd in cmds.iter() {
+            let batch = parse_batch(vec![json!({"set": ["a", 1]}), cmd.clone()]).unwrap();
+            assert_eq!(eval_json_cmd(batch, &mut db), Err(BAD_BATCH));
+            let batch = parse_batch(vec![json!({"get": "a"}), cmd.clone()]).unwrap();
+            assert_eq!(eval_json_query(batch, &db), Err(BAD_BATCH));
+        }
+        assert_eq!(db.get("a"), None);
+
+        remove_file("./lone.db").unwrap();
+        remove_file("./lone.keys").unwrap();
+    }
+
+    #[test]
+    fn cas_ok() {
+        let mut db = Database::open("./", "cas").unwrap();
+        assert_eq!(db.cas("k", None, json!(1)), Ok(1));
+        assert_eq!(db.cas("k", None, json!(2)), Err("version conflict"));
+        assert_eq!(eval(&mut db, "{\"getv\": \"k\"}"), Ok(json!({"value": 1, "version": 1})));
+        assert_eq!(eval(&mut db, "{\"cas\": [\"k\", 1, 2]}"), Ok(json!(2)));
+        assert_eq!(eval(&mut db, "{\"cas\": [\"k\", 1, 3]}"), Err("version conflict"));
+        db.del("k").unwrap();
+        assert_eq!(db.cas("k", Some(2), json!(4)), Err("version conflict"));
+        assert_eq!(db.cas("k", None, json!(4)), Ok(4));
+
+        // versions survive a restart
+        db.flush().unwrap();
+        let mut db = Database::open("./", "cas").unwrap();
+        assert_eq!(db.get_entry("k").map(Entry::version), Some(4));
+        assert_eq!(db.set("j", json!(5)).unwrap(), None);
+        assert_eq!(db.get_entry("j").map(Entry::version), Some(5));
+
+        remove_file("./cas.db").unwrap();
+        remove_file("./cas.keys").unwrap();
+    }
+
+    #[test]
+    fn expire_ok() {
+        let mut db = Database::open("./", "expire").unwrap();
+        db.set_ex("a", json!(1), Some(Duration::from_secs(100))).unwrap();
+        db.set("b", json!(2)).unwrap();
+        db.set("c", json!(3)).unwrap();
+        assert!(db.expire("c", Duration::from_secs(0)).unwrap());
+        assert!(!db.expire("d", Duration::from_secs(0)).unwrap());
+        assert_eq!(db.get("c"), None);
+        assert!(db.ttl("a").unwrap().unwrap() > Duration::from_secs(90));
+        assert_eq!(db.ttl("b"), Some(None));
+        assert_eq!(db.ttl("c"), None);
+        let watched = db.watch_seq("c");
+        assert_eq!(db.remove_expired(), 1);
+        assert!(db.watch_seq("c") > watched);
+
+        // a ttl near u64::MAX seconds is refused, or never expires if given directly
+        assert_eq!(db.eval(format!("{{\"set\": [\"e\", 1, {{\"ttl\": {}}}]}}", u64::MAX - 1)), Err("bad number"));
+        assert_eq!(db.eval(format!("{{\"expire\": [\"b\", {}]}}", MAX_SECS + 1)), Err("bad number"));
+        assert_eq!(db.eval(format!("{{\"set\": [\"e\", 1, {{\"ttl\": {}}}]}}", MAX_SECS)), Ok(JsonVal::Null));
+        assert!(db.ttl("e").unwrap().unwrap() > Duration::from_secs(MAX_SECS / 2));
+        db.set_ex("f", json!(1), Some(Duration::from_secs(u64::MAX - 1))).unwrap();
+        assert!(db.expire("f", Duration::from_secs(u64::MAX)).unwrap());
+        assert_eq!(db.get("f"), Some(&json!(1)));
+        assert_eq!(db.remove_expired(), 0);
+
+        // expiries survive a restart
+        db.flush().unwrap();
+        let mut db = Database::open("./", "expire").unwrap();
+        assert_eq!(db.get("c"), None);
+        assert!(db.ttl("a").unwrap().is_some());
+        assert!(db.persist("a").unwrap());
+        assert!(!db.persist("b").unwrap());
+
+        db.flush().unwrap();
+        let db = Database::open("./", "expire").unwrap();
+        assert_eq!(db.ttl("a"), Some(None));
+
+        remove_file("./expire.db").unwrap();
+        remove_file("./expire.keys").unwrap();
+    }
+
+    #[test]
+    fn retention_ok() {
+        let mut db = Database::open("./", "retention").unwrap();
+        let rows = (0..5).map(|x| obj! {"x" => x}).collect();
+        db.insert_table("capped".to_string(), rows).unwrap();
+        db.set_retention("capped", Some(Retention::MaxRows(3))).unwrap();
+        db.insert_table("capped".to_string(), vec![obj! {"x" => 5}]).unwrap();
+        let tbl = db.find_table("capped").unwrap();
+        assert_eq!(tbl.rows(), &[obj! {"x" => 3}, obj! {"x" => 4}, obj! {"x" => 5}][..]);
+
+        let now = now_millis();
+        let rows = vec![obj! {"t" => now - 10_000}, obj! {"t" => now}, obj! {"y" => 1}];
+        db.insert_table("aged".to_string(), rows).unwrap();
+        let retention = Retention::MaxAge { field: "t".to_string(), secs: 5 };
+        db.set_retention("aged", Some(retention.clone())).unwrap();
+        assert_eq!(db.find_table("aged").unwrap().len(), 2);
+        assert_eq!(db.set_retention("missing", None), Err("cannot find table"));
+        let huge = format!("{{\"retention\": [\"aged\", {{\"max_age\": {{\"field\": \"t\", \"secs\": {}}}}}]}}", MAX_SECS + 1);
+        assert_eq!(db.eval(huge), Err("bad number"));
+        // a span longer than the epoch keeps every row
+        db.insert_table("kept".to_string(), vec![obj! {"t" => 0}]).unwrap();
+        db.set_retention("kept", Some(Retention::MaxAge { field: "t".to_string(), secs: u64::MAX })).unwrap();
+        assert_eq!(db.enforce_retention().unwrap(), 0);
+        assert_eq!(db.find_table("kept").unwrap().len(), 1);
+
+        // pruned rows stay gone after a restart
+        db.flush().unwrap();
+        let db = Database::open("./", "retention").unwrap();
+        let tbl = db.find_table("capped").unwrap();
+        assert_eq!(tbl.retention(), Some(&Retention::MaxRows(3)));
+        assert_eq!(tbl.rows(), &[obj! {"x" => 3}, obj! {"x" => 4}, obj! {"x" => 5}][..]);
+        assert_eq!(db.find_table("aged").unwrap().retention(), Some(&retention));
+        assert_eq!(db.find_table("aged").unwrap().len(), 2);
+
+        remove_file("./retention.db").unwrap();
+        remove_file("./retention.keys").unwrap();
+        remove_file("./capped.table").unwrap();
+        remove_file("./capped.snapshot").unwrap();
+        remove_file("./aged.table").unwrap();
+        remove_file("./aged.snapshot").unwrap();
+        remove_file("./kept.table").unwrap();
+    }
+
+    #[test]
+    fn snapshot_ok() {
+        let mut db = Database::open("./", "snap").unwrap();
+        db.insert_table("snap".to_string(), vec![obj! {"x" => 1}, obj! {"x" => 2}]).unwrap();
+        assert_eq!(db.snapshot(Some("snap")), Ok(1));
+        db.insert_table("snap".to_string(), vec![obj! {"x" => 3}]).unwrap();
+        assert_eq!(db.snapshot(Some("missing")), Err("cannot find table"));
+
+        db.flush().unwrap();
+        let db = Database::open("./", "snap").unwrap();
+        assert_eq!(db.find_table("snap").unwrap().len(), 3);
+
+        // a crash after writing the snapshot but before truncating the log
+        write_snapshot("./snap.snapshot", LogFormat::Json, None, 2, db.find_table("snap").unwrap().rows()).unwrap();
+        db.flush().unwrap();
+        let mut db = Database::open("./", "snap").unwrap();
+        assert_eq!(db.find_table("snap").unwrap().len(), 3);
+        db.insert_table("snap".to_string(), vec![obj! {"x" => 4}]).unwrap();
+
+        db.flush().unwrap();
+        let db = Database::open("./", "snap").unwrap();
+        let rows: Vec<Row> = (1..5).map(|x| obj! {"x" => x}).collect();
+        assert_eq!(db.find_table("snap").unwrap().rows(), &rows[..]);
+
+        remove_file("./snap.db").unwrap();
+        remove_file("./snap.keys").unwrap();
+        remove_file("./snap.table").unwrap();
+        remove_file("./snap.snapshot").unwrap();
+    }
+
+    #[test]
+    fn durability_ok() {
+        let mut db = Database::open("./", "durable").unwrap();
+        assert_eq!(db.durability(), Durability::Os);
+        db.set_durability("always".parse().unwrap());
+        assert_eq!(db.eval("{\"set\": [\"a\", 1]}"), Ok(JsonVal::Null));
+        assert_eq!(db.eval("{\"insert\": [\"durable\", {\"x\": 1}]}"), Ok(json!(1)));
+        db.sync().unwrap();
+        assert!("weekly".parse::<Durability>().is_err());
+
+        remove_file("./durable.db").unwrap();
+        remove_file("./durable.keys").unwrap();
+        remove_file("./durable.table").unwrap();
+    }
+
+    #[test]
+    fn group_commit_ok() {
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::{Barrier, RwLock};
+        use std::thread;
+
+        let mut db = Database::open("./", "group").unwrap();
+        db.set_durability(Durability::Always);
+        let db = RwLock::new(db);
+        let runs = AtomicUsize::new(0);
+        // every write is applied before any of them commits
+        let applied = Barrier::new(8);
+        thread::scope(|s| {
+            for i in 0..8 {
+                let (db, runs, applied) = (&db, &runs, &applied);
+                s.spawn(move || {
+                    let cmd = parse_json_str(format!("{{\"set\": [\"k{}\", {}]}}", i, i)).unwrap();
+                    let (_, ticket) = db.write().unwrap().apply(cmd).unwrap();
+                    applied.wait();
+                    let commits = db.read().unwrap().commits();
+                    commits.wait(ticket, || {
+                        runs.fetch_add(1, Ordering::SeqCst);
+                        db.read().unwrap().commit_logs()
+                    }).unwrap();
+                });
+            }
+        });
+        // the first commit covers all eight writes
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        let commits = db.read().unwrap().commits();
+        commits.wait(8, || panic!("already committed")).unwrap();
+
+        let db = Database::open("./", "group").unwrap();
+        for i in 0..8 {
+            assert_eq!(db.get(&format!("k{}", i)), Some(&json!(i)));
+        }
+
+        remove_file("./group.db").unwrap();
+        remove_file("./group.keys").unwrap();
+    }
+
+    #[test]
+    fn log_format_ok() {
+        let mut db = Database::open("./", "binfmt").unwrap();
+        assert_eq!(db.eval("{\"set\": [\"a\", 1]}"), Ok(JsonVal::Null));
+        assert_eq!(db.eval("{\"insert\": [\"oldfmt\", {\"x\": 1}]}"), Ok(json!(1)));
+        db.set_log_format(LogFormat::Bincode).unwrap();
+        assert_eq!(db.eval("{\"insert\": [\"newfmt\", {\"x\": 2}]}"), Ok(json!(1)));
+
+        let mut db = Database::open("./", "binfmt").unwrap();
+        assert_eq!(db.log_format(), LogFormat::Bincode);
+        assert_eq!(db.find_table("oldfmt").unwrap().log.format(), LogFormat::Json);
+        assert_eq!(db.find_table("newfmt").unwrap().log.format(), LogFormat::Bincode);
+        db.convert_logs().unwrap();
+
+        let db = Database::open("./", "binfmt").unwrap();
+        assert_eq!(db.key_log.format(), LogFormat::Bincode);
+        assert_eq!(db.find_table("oldfmt").unwrap().log.format(), LogFormat::Bincode);
+        assert_eq!(db.find_table("oldfmt").unwrap().rows(), &[obj! {"x" => 1}]);
+        assert_eq!(db.get("a"), Some(&json!(1)));
+
+        remove_file("./binfmt.db").unwrap();
+        remove_file("./binfmt.keys").unwrap();
+        remove_file("./oldfmt.table").unwrap();
+        remove_file("./newfmt.table").unwrap();
+    }
+
+    #[test]
+    fn table_lifecycle_ok() {
+        let mut db = Database::open("./", "lifecycle").unwrap();
+        assert_eq!(db.eval("{\"insert\": [\"life_a\", [{\"x\": 1}, {\"x\": 2}]]}"), Ok(json!(2)));
+        assert_eq!(db.eval("{\"retention\": [\"life_a\", {\"max_rows\": 10}]}"), Ok(json!("OK")));
+        assert_eq!(db.eval("{\"rename\": [\"life_a\", \"life_b\"]}"), Ok(json!("OK")));
+        assert_eq!(db.eval("{\"rename\": [\"life_a\", \"life_c\"]}"), Err("cannot find table"));
+        assert_eq!(db.eval("{\"clone\": [\"life_b\", \"life_c\"]}"), Ok(json!(2)));
+        assert_eq!(db.eval("{\"clone\": [\"life_b\", \"life_c\"]}"), Err("table already exists"));
+        assert_eq!(db.eval("{\"truncate\": \"life_b\"}"), Ok(json!(2)));
+        assert_eq!(db.eval("{\"insert\": [\"life_b\", {\"x\": 3}]}"), Ok(json!(1)));
+        assert_eq!(db.exec(JsonCmd::Batch(vec![JsonCmd::Truncate("life_b".to_string())])), Err(BAD_TABLE_TXN));
+        let batch = vec![JsonCmd::Insert("life_b".to_string(), vec![obj! {"x" => 4}]), JsonCmd::Snapshot(Some("life_b".to_string()))];
+        assert_eq!(db.exec(JsonCmd::Batch(batch)), Err(BAD_TABLE_TXN));
+        let batch = vec![JsonCmd::Insert("life_b".to_string(), vec![obj! {"x" => 4}]), JsonCmd::Retention("life_b".to_string(), None)];
+        assert_eq!(db.exec(JsonCmd::Batch(batch)), Err(BAD_TABLE_TXN));
+
+        let db = Database::open("./", "lifecycle").unwrap();
+        assert!(db.find_table("life_a").is_none());
+        assert_eq!(db.find_table("life_b").unwrap().rows(), &[obj! {"x" => 3}]);
+        assert_eq!(db.find_table("life_c").unwrap().rows(), &[obj! {"x" => 1}, obj! {"x" => 2}]);
+        assert_eq!(db.find_table("life_c").unwrap().retention(), Some(&Retention::MaxRows(10)));
+
+        remove_file("./lifecycle.db").unwrap();
+        remove_file("./lifecycle.keys").unwrap();
+        remove_file("./life_b.table").unwrap();
+        remove_file("./life_b.snapshot").unwrap();
+        remove_file("./life_c.table").unwrap();
+    }
+
+    #[test]
+    fn recover_ok() {
+        let mut db = Database::open("./", "pitr").unwrap();
+        assert_eq!(db.eval("{\"set\": [\"a\", 1]}"), Ok(JsonVal::Null));
+        assert_eq!(db.eval("{\"insert\": [\"pitr_t\", {\"x\": 1}]}"), Ok(json!(1)));
+        let point = db.seq;
+        assert_eq!(db.eval("{\"set\": [\"a\", 2]}"), Ok(json!(1)));
+        assert_eq!(db.eval("{\"insert\": [\"pitr_t\", {\"x\": 2}]}"), Ok(json!(1)));
+        db.exec(JsonCmd::Batch(vec![JsonCmd::Del("a".to_string()), JsonCmd::Set("b".to_string(), json!(3))])).unwrap();
+        let last = db.seq;
+        drop(db);
+
+        let db = Database::open("./", "pitr").unwrap();
+        assert_eq!(db.seq, last);
+        drop(db);
+        let report = recover("./", "pitr", RecoveryPoint::Seq(point), None).unwrap();
+        assert_eq!(report.iter().map(|(_, n)| n).sum::<usize>(), 7);
+        let db = Database::open("./", "pitr").unwrap();
+        assert_eq!(db.get("a"), Some(&json!(1)));
+        assert_eq!(db.get("b"), None);
+        assert_eq!(db.find_table("pitr_t").unwrap().rows(), &[obj! {"x" => 1}]);
+        assert_eq!(db.seq, point);
+        drop(db);
+
+        let mut db = Database::open("./", "pitr").unwrap();
+        assert_eq!(db.eval("{\"truncate\": \"pitr_t\"}"), Ok(json!(1)));
+        drop(db);
+        let err = recover("./", "pitr", RecoveryPoint::Seq(point), None);
+        assert_eq!(err, Err("table was snapshotted after the recovery point"));
+        assert_eq!("time:5".parse(), Ok(RecoveryPoint::Time(5)));
+        assert!("seq".parse::<RecoveryPoint>().is_err());
+
+        remove_file("./pitr.db").unwrap();
+        remove_file("./pitr.keys").unwrap();
+        remove_file("./pitr.keys.before-recovery").unwrap();
+        remove_file("./pitr_t.table").unwrap();
+        remove_file("./pitr_t.table.before-recovery").unwrap();
+        remove_file("./pitr_t.snapshot").unwrap();
+    }
+
+    #[test]
+    fn import_ok() {
+        fs::write("./import_ok.csv", "sym,px\nabc,1.5\nxyz\ndef,2\n").unwrap();
+        let mut db = Database::open("./", "imp").unwrap();
+        assert_eq!(db.eval("{\"import\": [\"ticks\", \"import_ok.csv\"]}"), Err("cannot open import file"));
+        assert_eq!(db.eval("{\"import\": [\"ticks\", \"/etc/passwd\"]}"), Err(BAD_PATH));
+        assert_eq!(db.eval("{\"import\": [\"ticks\", \"../import_ok.csv\"]}"), Err(BAD_PATH));
+        db.set_import_dir("./");
+        let res = db.eval("{\"import\": [\"ticks\", \"./import_ok.csv\"]}").unwrap();
+        assert_eq!(res, json!({"rows": 2, "rejected": 1, "lines": [3]}));
+        let res = db.eval("{\"import\": [\"ticks\", \"./import_ok.csv\", {\"header\": false, \"delimiter\": \";\"}]}").unwrap();
+        assert_eq!(res, json!({"rows": 4, "rejected": 0, "lines": []}));
+        assert_eq!(db.eval("{\"import\": [\"ticks\", \"./import_ok.csv\", {\"bad\": 1}]}"), Err("bad type"));
+        assert_eq!(db.find_table("ticks").unwrap().len(), 6);
+
+        db.flush().unwrap();
+        let db = Database::open("./", "imp").unwrap();
+        let rows = db.find_table("ticks").unwrap().rows();
+        assert_eq!(rows[0], obj! {"sym" => "abc", "px" => 1.5});
+        assert_eq!(rows[2], obj! {"c1" => "sym,px"});
+
+        remove_file("./import_ok.csv").unwrap();
+        remove_file("./imp.db").unwrap();
+        remove_file("./imp.keys").unwrap();
+        remove_file("./ticks.table").unwrap();
+    }
+
+    #[test]
+    fn export_ok() {
+        let mut db = Database::open("./", "exp").unwrap();
+        db.set_export_dir("./exp_out");
+        db.insert_table("exp_t".to_string(), vec![obj! {"sym" => "a", "px" => 1}, obj! {"sym" => "b", "px" => 2}]).unwrap();
+        let res = db.eval("{\"export\": [\"exp_t\", \"./exp_a.csv\", {\"columns\": [\"sym\", \"px\"]}]}");
+        assert_eq!(res, Ok(json!(2)));
+        assert_eq!(fs::read_to_string("./exp_out/exp_a.csv").unwrap(), "sym,px\na,1\nb,2\n");
+        let qry = json!({"export": [{"selects": [{"Sum": {"Get": "px"}}], "from": "exp_t"}, "daily/exp_b.ndjson"]});
+        assert_eq!(db.eval(qry.to_string()), Ok(json!(1)));
+        assert_eq!(fs::read_to_string("./exp_out/daily/exp_b.ndjson").unwrap(), "{\"sum(px)\":3.0}\n");
+        assert_eq!(db.eval("{\"export\": [\"missing\", \"./exp_c.csv\"]}"), Err("cannot find table"));
+        assert_eq!(db.eval("{\"export\": [\"exp_t\", \"/tmp/exp_d.csv\"]}"), Err(BAD_PATH));
+        assert_eq!(db.eval("{\"export\": [\"exp_t\", \"daily/../../exp_d.csv\"]}"), Err(BAD_PATH));
+
+        fs::remove_dir_all("./exp_out").unwrap();
+        remove_file("./exp.db").unwrap();
+        remove_file("./exp.keys").unwrap();
+        remove_file("./exp_t.table").unwrap();
+    }
+
+    #[test]
+    fn backup_ok() {
+        let mut db = Database::open("./", "bk").unwrap();
+        assert_eq!(db.eval("{\"set\": [\"a\", 1]}"), Ok(JsonVal::Null));
+        assert_eq!(db.eval("{\"set\": [\"b\", 2, {\"ttl\": 60}]}"), Ok(JsonVal::Null));
+        assert_eq!(db.eval("{\"insert\": [\"bk_t\", [{\"x\": 1}, {\"x\": 2}]]}"), Ok(json!(2)));
+        assert_eq!(db.eval("{\"retention\": [\"bk_t\", {\"max_rows\": 5}]}"), Ok(json!("OK")));
+        let seq = db.seq;
+        db.set_backup_dir("./bk_backups");
+        assert_eq!(db.eval("{\"backup\": \"daily\"}"), Ok(json!(seq)));
+        assert_eq!(db.eval("{\"backup\": \"daily\"}"), Err("backup directory already holds a backup"));
+        assert_eq!(db.eval("{\"backup\": \"/tmp/bk\"}"), Err(BAD_PATH));
+        assert_eq!(db.eval("{\"backup\": \"..\"}"), Err(BAD_PATH));
+        assert_eq!(db.eval("{\"set\": [\"a\", 3]}"), Ok(json!(1)));
+        assert_eq!(
+            db.exec(JsonCmd::Batch(vec![JsonCmd::Backup("./bk_other".to_string())])),
+            Err(BAD_BATCH)
+        );
+
+        fs::create_dir_all("./bk_restore").unwrap();
+        assert_eq!(restore("./bk_backups/daily", "./bk_restore", "bk", None).unwrap().len(), 4);
+        assert!(restore("./bk_backups/daily", "./bk_restore", "bk", None).is_err());
+        let mut db = Database::open("./bk_restore", "bk").unwrap();
+        assert_eq!(db.get("a"), Some(&json!(1)));
+        assert_eq!(db.get("b"), Some(&json!(2)));
+        assert!(db.ttl("b").unwrap().is_some());
+        assert_eq!(db.seq, seq);
+        let tbl = db.find_table("bk_t").unwrap();
+        assert_eq!(tbl.rows(), &[obj! {"x" => 1}, obj! {"x" => 2}]);
+        assert_eq!(tbl.retention(), Some(&Retention::MaxRows(5)));
+        assert_eq!(db.eval("{\"insert\": [\"bk_t\", {\"x\": 3}]}"), Ok(json!(1)));
+        let db = Database::open("./bk_restore", "bk").unwrap();
+        assert_eq!(db.find_table("bk_t").unwrap().len(), 3);
+
+        remove_file("./bk.db").unwrap();
+        remove_file("./bk.keys").unwrap();
+        remove_file("./bk_t.table").unwrap();
+        fs::remove_dir_all("./bk_backups").unwrap();
+        fs::remove_dir_all("./bk_restore").unwrap();
+    }
+
+    #[test]
+    fn online_backup_ok() {
+        let mut db = Database::open("./", "obk").unwrap();
+        assert_eq!(db.eval("{\"insert\": [\"obk_a\", [{\"x\": 1}, {\"x\": 2}]]}"), Ok(json!(2)));
+        assert_eq!(db.eval("{\"insert\": [\"obk_b\", {\"y\": 1}]}"), Ok(json!(1)));
+        assert_eq!(db.eval("{\"snapshot\": \"obk_a\"}"), Ok(json!(1)));
+        assert_eq!(db.eval("{\"insert\": [\"obk_a\", {\"x\": 3}]}"), Ok(json!(1)));
+        drop(db);
+
+        let (mut db, sources) = Database::open_deferred("./", "obk", None).unwrap();
+        db.keep_cold(sources);
+        assert_eq!(db.eval("{\"insert\": [\"obk_b\", {\"y\": 2}]}"), Ok(json!(1)));
+        assert!(db.find_table("obk_a").is_none());
+        let (image, seq) = (db.backup_image().unwrap(), db.seq);
+        // writes once the image is taken are left out, even if they warm a
+        // cold table or replace a log
+        assert_eq!(db.eval("{\"insert\": [\"obk_a\", {\"x\": 4}]}"), Ok(json!(1)));
+        assert_eq!(db.eval("{\"insert\": [\"obk_b\", {\"y\": 3}]}"), Ok(json!(1)));
+        assert_eq!(db.eval("{\"snapshot\": null}"), Ok(json!(2)));
+        assert_eq!(image.write("./obk_backups"), Ok(seq));
+
+        fs::create_dir_all("./obk_restore").unwrap();
+        restore("./obk_backups", "./obk_restore", "obk", None).unwrap();
+        let db = Database::open("./obk_restore", "obk").unwrap();
+        assert_eq!(db.find_table("obk_a").unwrap().rows(), &[obj! {"x" => 1}, obj! {"x" => 2}, obj! {"x" => 3}]);
+        assert_eq!(db.find_table("obk_b").unwrap().rows(), &[obj! {"y" => 1}, obj! {"y" => 2}]);
+
+        for file in ["obk.db", "obk.keys", "obk_a.table", "obk_a.snapshot", "obk_b.table", "obk_b.snapshot"] {
+            remove_file(file).unwrap();
+        }
+        fs::remove_dir_all("./obk_backups").unwrap();
+        fs::remove_dir_all("./obk_restore").unwrap();
+    }
+
+    #[test]
+    fn encryption_ok() {
+        let mut db = Database::open("./", "enc").unwrap();
+        assert_eq!(db.eval("{\"set\": [\"a\", \"secret\"]}"), Ok(JsonVal::Null));
+        assert_eq!(db.eval("{\"insert\": [\"enc_t\", {\"x\": \"needle\"}]}"), Ok(json!(1)));
+        assert_eq!(db.eval("{\"snapshot\": \"enc_t\"}"), Ok(json!(1)));
+        assert_eq!(db.eval("{\"insert\": [\"enc_t\", {\"x\": \"thread\"}]}"), Ok(json!(1)));
+
+        // a plain database is encrypted once opened with a key
+        let key = Cipher::new(&[1; 32]);
+        let mut db = Database::open_with("./", "enc", 1, Some(&key)).unwrap();
+        db.set_log_format(LogFormat::Bincode).unwrap();
+        assert_eq!(db.eval("{\"set\": [\"b\", \"hidden\"]}"), Ok(JsonVal::Null));
+        assert_eq!(db.eval("{\"insert\": [\"enc_u\", {\"y\": \"straw\"}]}"), Ok(json!(1)));
+        for path in &["./enc.db", "./enc.keys", "./enc_t.table", "./enc_t.snapshot", "./enc_u.table"] {
+            let buf = String::from_utf8_lossy(&fs::read(path).unwrap()).into_owned();
+            for word in &["secret", "needle", "thread", "hidden", "straw", "enc_t"] {
+                assert!(!buf.contains(word), "{} found in {}", word, path);
+            }
+        }
+
+        let db = Database::open_with("./", "enc", 1, Some(&key)).unwrap();
+        assert_eq!(db.get("a"), Some(&json!("secret")));
+        assert_eq!(db.get("b"), Some(&json!("hidden")));
+        assert_eq!(db.find_table("enc_t").unwrap().rows(), &[obj! {"x" => "needle"}, obj! {"x" => "thread"}]);
+        assert_eq!(db.find_table("enc_u").unwrap().rows(), &[obj! {"y" => "straw"}]);
+        assert_eq!(Database::open("./", "enc").err(), Some(ENCRYPTED));
+        assert_eq!(Database::open_with("./", "enc", 1, Some(&Cipher::new(&[2; 32]))).err(), Some(WRONG_KEY));
+        assert!(repair("./", "enc", None).is_err());
+        assert!(repair("./", "enc", Some(&key)).unwrap().iter().all(|(_, dropped)| *dropped == 0));
+
+        remove_file("./enc.db").unwrap();
+        remove_file("./enc.keys").unwrap();
+        remove_file("./enc_t.table").unwrap();
+        remove_file("./enc_t.snapshot").unwrap();
+        remove_file("./enc_u.table").unwrap();
+    }
+
+    #[test]
+    fn dates_ok() {
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        assert_eq!(format_date(-1), "1969-12-31");
+        assert_eq!(parse_date("2020-05-20"), Some(18402));
+        assert_eq!(format_date(18321), "2020-02-29");
+        assert_eq!(parse_date("2020-02-30"), None);
+        assert_eq!(parse_date("2020-5-20"), None);
+        assert_eq!(split_partition("ticks.2020-05-20"), Some(("ticks", "2020-05-20")));
+        assert_eq!(split_partition("ticks.2020-13-01"), None);
+    }
+
+    #[test]
+    fn partition_ok() {
+        let mut db = Database::open("./", "part").unwrap();
+        assert_eq!(db.eval("{\"partition\": [\"part_t\", {\"field\": \"t\"}]}"), Ok(json!("OK")));
+        assert_eq!(db.eval("{\"partition\": [\"part_t\", {\"field\": \"t\"}]}"), Err("table is already partitioned"));
+        let rows = json!([
+            {"t": 1589846400001u64, "x": 1},
+            {"t": 1589932800005u64, "x": 2},
+            {"t": 1590019200010u64, "x": 3},
+            {"t": 1589932800007u64, "x": 4},
+        ]);
+        assert_eq!(db.eval(json!({"insert": ["part_t", rows]}).to_string()), Ok(json!(4)));
+        assert!(db.eval("{\"insert\": [\"part_t\", {\"x\": 5}]}").is_err());
+        assert_eq!(db.eval("{\"partitions\": \"part_t\"}"), Ok(json!(["2020-05-19", "2020-05-20", "2020-05-21"])));
+        assert_eq!(db.find_table("part_t.2020-05-20").unwrap().len(), 2);
+        assert_eq!(db.eval("{\"rename\": [\"part_t\", \"part_u\"]}"), Err(BAD_PARTITION_CHANGE));
+
+        let qry = json!({"selects": [{"Sum": {"Get": "x"}}], "from": "part_t", "time": {"field": "t", "from": 1589932800000u64, "to": 1590019200000u64}});
+        let time: TimeRange = serde_json::from_value(qry["time"].clone()).unwrap();
+        assert_eq!(db.scan_tables("part_t", Some(&time)), vec!["part_t.2020-05-20".to_string()]);
+        assert_eq!(db.eval(json!({"query": qry}).to_string()), Ok(json!([{"sum(x)": 6.0}])));
+        let qry = json!({"selects": [{"Get": "x"}], "from": "part_t", "time": {"field": "t", "from": 1589846400005u64}});
+        assert_eq!(db.eval(json!({"query": qry}).to_string()), Ok(json!([{"x": 2}, {"x": 4}, {"x": 3}])));
+
+        db.flush().unwrap();
+        let mut db = Database::open("./", "part").unwrap();
+        assert_eq!(db.partition_dates("part_t").unwrap().len(), 3);
+        db.set_export_dir("./part_out");
+        assert_eq!(db.eval("{\"archive_partitions\": [\"part_t\", \"2020-05-20\", \"/tmp\"]}"), Err(BAD_PATH));
+        assert_eq!(db.eval("{\"archive_partitions\": [\"part_t\", \"2020-05-20\", \"../part\"]}"), Err(BAD_PATH));
+        assert_eq!(db.eval("{\"archive_partitions\": [\"part_t\", \"2020-05-20\", \"archive\"]}"), Ok(json!(1)));
+        assert_eq!(
+            fs::read_to_string("./part_out/archive/part_t.2020-05-19.ndjson").unwrap(),
+            "{\"t\":1589846400001,\"x\":1}\n"
+        );
+        assert_eq!(db.eval("{\"drop_partitions\": [\"part_t\", \"2020-05-21\"]}"), Ok(json!(1)));
+        assert_eq!(db.eval("{\"drop_partitions\": [\"part_t\", \"May 21\"]}"), Err(BAD_DATE));
+        assert_eq!(db.partition_dates("part_t"), Ok(vec!["2020-05-21".to_string()]));
+        assert!(!Path::new("./part_t.2020-05-20.table").exists());
+        assert!(db.delete_table("part_t").unwrap());
+        assert!(!Path::new("./part_t.2020-05-21.table").exists());
+        assert_eq!(db.partition_dates("part_t"), Err(NOT_PARTITIONED));
+
+        remove_file("./part.db").unwrap();
+        remove_file("./part.keys").unwrap();
+        fs::remove_dir_all("./part_out").unwrap();
+    }
+
+    #[test]
+    fn loading_ok() {
+        let mut db = Database::open("./", "loading").unwrap();
+        assert_eq!(db.eval("{\"insert\": [\"loading_a\", {\"x\": 1}]}"), Ok(json!(1)));
+        assert_eq!(db.eval("{\"insert\": [\"loading_b\", [{\"x\": 1}, {\"x\": 2}]]}"), Ok(json!(2)));
+        let seq = db.seq;
+        drop(db);
+
+        let (mut db, sources) = Database::open_deferred("./", "loading", None).unwrap();
+        assert!(db.is_loading());
+        assert_eq!(db.eval("{\"insert\": [\"loading_a\", {\"x\": 2}]}"), Err(TABLE_LOADING));
+        assert_eq!(db.eval("{\"rename\": [\"loading_b\", \"loading_c\"]}"), Err(TABLE_LOADING));
+        assert_eq!(db.eval("{\"backup\": \"./loading_backup\"}"), Err(TABLE_LOADING));
+        assert_eq!(db.eval("{\"set\": [\"k\", 1]}"), Ok(JsonVal::Null));
+        let db = Mutex::new(db);
+        load_tables(sources, 2, |table| db.lock().unwrap().loaded(table)).unwrap();
+        let mut db = db.into_inner().unwrap();
+        assert!(!db.is_loading());
+        assert!(db.seq > seq);
+        assert_eq!(db.find_table("loading_b").unwrap().len(), 2);
+        assert_eq!(db.eval("{\"insert\": [\"loading_a\", {\"x\": 2}]}"), Ok(json!(1)));
+
+        let db = Database::open_with("./", "loading", 1, None).unwrap();
+        assert_eq!(db.find_table("loading_a").unwrap().len(), 2);
+
+        remove_file("./loading.db").unwrap();
+        remove_file("./loading.keys").unwrap();
+        remove_file("./loading_a.table").unwrap();
+        remove_file("./loading_b.table").unwrap();
+    }
+
+    #[test]
+    fn lazy_tables_ok() {
+        let mut db = Database::open("./", "lazy").unwrap();
+        assert_eq!(db.eval("{\"insert\": [\"lazy_a\", [{\"x\": 1}, {\"x\": 2}]]}"), Ok(json!(2)));
+        assert_eq!(db.eval("{\"insert\": [\"lazy_b\", {\"x\": \"a longer value\"}]}"), Ok(json!(1)));
+        drop(db);
+
+        let (mut db, sources) = Database::open_deferred("./", "lazy", None).unwrap();
+        db.keep_cold(sources);
+        assert!(!db.is_loading());
+        assert_eq!(db.table_memory(), 0);
+        assert_eq!(db.eval("{\"insert\": [\"lazy_a\", {\"x\": 3}]}"), Ok(json!(1)));
+        let size = db.find_table("lazy_a").unwrap().size();
+        assert_eq!(db.table_memory(), size);
+        assert!(db.find_table("lazy_b").is_none());
+
+        db.set_table_budget(size + 1).unwrap();
+        db.warm_table("lazy_b").unwrap();
+        assert!(db.find_table("lazy_a").is_none());
+        assert_eq!(db.find_table("lazy_b").unwrap().len(), 1);
+        assert_eq!(db.eval("{\"clone\": [\"lazy_b\", \"lazy_a\"]}"), Err("table already exists"));
+        let qry = Query::from(vec![Expr::Get("x".to_string())], "lazy_a".to_string());
+        assert!(qry.scan(&db).is_none());
+        qry.warm(&mut db).unwrap();
+        assert_eq!(qry.scan(&db).unwrap().unwrap().len(), 3);
+        assert_eq!(qry.exec(&mut db).unwrap().len(), 3);
+        assert!(db.find_table("lazy_b").is_none());
+        assert!(db.delete_table("lazy_b").unwrap());
+        assert!(!Path::new("./lazy_b.table").exists());
+
+        let db = Database::open("./", "lazy").unwrap();
+        assert!(db.find_table("lazy_b").is_none());
+        assert_eq!(db.find_table("lazy_a").unwrap().len(), 3);
+
+        remove_file("./lazy.db").unwrap();
+        remove_file("./lazy.keys").unwrap();
+        remove_file("./lazy_a.table").unwrap();
+    }
+
+    #[test]
+    fn maxmemory_ok() {
+        let mut db = Database::open("./", "mem").unwrap();
+        for key in &["k1", "k2", "k3", "k4"] {
+            db.set(*key, json!("a value of some length")).unwrap();
+        }
+        let size = db.keys.iter().map(|(key, entry)| entry.size(key)).sum::<usize>();
+        assert_eq!(db.used_memory(), size);
+        db.set("k1", json!(1)).unwrap();
+        db.del("k4").unwrap();
+        let size = db.keys.iter().map(|(key, entry)| entry.size(key)).sum::<usize>();
+        assert_eq!(db.used_memory(), size);
+
+        db.set_max_memory(db.used_memory(), EvictionPolicy::NoEviction);
+        assert_eq!(db.eval("{\"set\": [\"k5\", 5]}"), Ok(JsonVal::Null));
+        assert_eq!(db.eval("{\"set\": [\"k6\", 6]}"), Err(OUT_OF_MEMORY));
+        assert_eq!(db.eval("{\"del\": \"k5\"}"), Ok(json!(5)));
+
+        // k1 is read most often, then most recently
+        db.get("k1");
+        db.get("k1");
+        db.set_max_memory(db.used_memory() - 1, EvictionPolicy::AllKeysLfu);
+        assert_eq!(db.eval("{\"set\": [\"k6\", 6]}"), Ok(JsonVal::Null));
+        assert_eq!(db.keys.len(), 3);
+        assert!(db.keys.contains_key("k1"));
+        db.keys["k1"].access.last.store(now_millis() + 1000, std::sync::atomic::Ordering::Relaxed);
+        db.set_max_memory(db.used_memory() - 1, EvictionPolicy::AllKeysLru);
+        assert_eq!(db.eval("{\"set\": [\"k5\", 5]}"), Ok(JsonVal::Null));
+        assert!(db.keys.contains_key("k1"));
+        assert_eq!(db.eval("{\"set\": [\"k7\", 7, {\"ttl\": 60}]}"), Ok(JsonVal::Null));
+        db.set_max_memory(db.used_memory() - 1, EvictionPolicy::VolatileTtl);
+        assert_eq!(db.eval("{\"set\": [\"k8\", 8]}"), Ok(JsonVal::Null));
+        assert!(db.get("k7").is_none());
+        let stats = db.eval("{\"stats\": null}").unwrap();
+        assert!(stats["evicted_keys"].as_u64().unwrap() >= 3);
+        assert_eq!(stats["maxmemory_policy"], json!("volatile-ttl"));
+        let keys: Vec<String> = db.keys.keys().cloned().collect();
+
+        let db = Database::open("./", "mem").unwrap();
+        assert_eq!(db.keys.keys().cloned().collect::<Vec<_>>(), keys);
+
+        remove_file("./mem.db").unwrap();
+        remove_file("./mem.keys").unwrap();
+    }
+
+    #[test]
+    fn names_ok() {
+        assert!(is_valid_name("ticks_2020-01"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("a.b"));
+        assert!(!is_valid_name(&"x".repeat(65)));
+        assert_eq!(Database::open("./", "../names").err(), Some(BAD_NAME));
+
+        let mut db = Database::open("./", "names").unwrap();
+        assert_eq!(db.eval("{\"insert\": [\"../names_escape\", {\"x\": 1}]}"), Err(BAD_NAME));
+        assert!(!Path::new("../names_escape.table").exists());
+        assert_eq!(db.eval("{\"insert\": [\"names_a\", {\"x\": 1}]}"), Ok(json!(1)));
+        assert_eq!(db.eval("{\"rename\": [\"names_a\", \"/tmp/names_b\"]}"), Err(BAD_NAME));
+        assert_eq!(db.eval("{\"clone\": [\"names_a\", \"names a\"]}"), Err(BAD_NAME));
+
+        remove_file("./names.db").unwrap();
+        remove_file("./names.keys").unwrap();
+        remove_file("./names_a.table").unwrap();
+    }
+
     #[test]
     fn delete_table_ok() {
         // populate db with test table
@@ -290,6 +2620,7 @@ mod tests {
         // assert state
         assert_eq!(db.tables.len(), 0);
         remove_file("./test3.db").unwrap();
+        remove_file("./test3.keys").unwrap();
     }
 
     #[test]
@@ -319,6 +2650,8 @@ mod tests {
         assert_eq!(tbl.rows[5], obj! {"x" => "t"});
 
         remove_file("./append.db").unwrap();
+
+        remove_file("./append.keys").unwrap();
         remove_file("./append.table").unwrap();
     }
 
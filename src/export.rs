@@ -0,0 +1,303 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use parquet::basic::{LogicalType, Repetition, Type as PhysicalType};
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DoubleType, Int64Type};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::Type;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonVal};
+
+use crate::import::FileFormat;
+use crate::query::Query;
+use crate::{Res, Row};
+
+/// What an export writes out: every row of a table, or the result of a query.
+///
+/// Written as `"ticks"` or `{"selects": [{"Get": "px"}], "from": "ticks"}`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ExportSource {
+    Table(String),
+    Query(Query),
+}
+
+/// Options for the `export` command, e.g. `{"columns": ["sym", "px"]}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportOptions {
+    // guessed from the file extension if not given
+    pub format: Option<FileFormat>,
+    // the fields to write, in order; every field found in the rows if not given
+    pub columns: Option<Vec<String>>,
+    // CSV only: the field separator, a tab for `.tsv` files and `,` otherwise
+    pub delimiter: Option<char>,
+    // CSV only: whether to start with a line naming the columns
+    pub header: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self { format: None, columns: None, delimiter: None, header: true }
+    }
+}
+
+/// How many rows go in each Parquet row group.
+const ROW_GROUP_ROWS: usize = 64 * 1024;
+
+/// Writes rows to a new CSV, NDJSON or Parquet file, returning how many were
+/// written.
+/// An existing file is never overwritten, and a file that cannot be written
+/// in full is removed rather than left half written.
+pub fn write_file<P: AsRef<Path>>(path: P, rows: &[Row], opts: &ExportOptions) -> Res<usize> {
+    let path = path.as_ref();
+    let format = opts.format.unwrap_or_else(|| FileFormat::of(path));
+    let delimiter = match opts.delimiter {
+        Some(c) if !c.is_ascii() => return Err("delimiter must be a single ascii character"),
+        Some(c) => c as u8,
+        None if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("tsv")) => b'\t',
+        None => b',',
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|_| "cannot create export directory")?;
+    }
+    let file = match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => return Err("export file already exists"),
+        Err(err) => {
+            eprintln!("cannot create {:?}; error = {:?}", path, err);
+            return Err("cannot create export file");
+        }
+    };
+    let columns = match opts.columns {
+        Some(ref columns) => columns.clone(),
+        None => columns(rows),
+    };
+    let res = match format {
+        FileFormat::Csv => write_csv(file, rows, &columns, delimiter, opts.header),
+        FileFormat::Ndjson => write_ndjson(file, rows, opts.columns.as_ref().map(|_| &columns[..])),
+        FileFormat::Parquet => write_parquet(file, rows, &columns),
+    };
+    res.map_err(|err| {
+        eprintln!("cannot write {:?}; error = {:?}", path, err);
+        let _ = fs::remove_file(path);
+        "cannot write export file"
+    })?;
+    Ok(rows.len())
+}
+
+/// Every field found in the rows, in the order first seen.
+fn columns(rows: &[Row]) -> Vec<String> {
+    let mut columns: Vec<String> = Vec::new();
+    for row in rows {
+        for key in row.keys() {
+            if !columns.contains(key) {
+                columns.push(key.clone());
+            }
+        }
+    }
+    columns
+}
+
+fn write_csv<W: Write>(out: W, rows: &[Row], columns: &[String], delimiter: u8, header: bool) -> io::Result<()> {
+    let mut writer = csv::WriterBuilder::new().delimiter(delimiter).from_writer(out);
+    if header {
+        writer.write_record(columns)?;
+    }
+    for row in rows {
+        writer.write_record(columns.iter().map(|col| field(row.get(col))))?;
+    }
+    writer.flush()
+}
+
+/// Formats a value as a CSV field: strings as they are, a missing value or
+/// `null` as an empty field and anything else as JSON.
+fn field(val: Option<&JsonVal>) -> String {
+    match val {
+        None | Some(JsonVal::Null) => String::new(),
+        Some(JsonVal::String(s)) => s.clone(),
+        Some(val) => val.to_string(),
+    }
+}
+
+fn write_ndjson<W: Write>(out: W, rows: &[Row], columns: Option<&[String]>) -> io::Result<()> {
+    let mut out = BufWriter::new(out);
+    for row in rows {
+        let line = match columns {
+            Some(columns) => {
+                let row: Map<String, JsonVal> = columns
+                    .iter()
+                    .filter_map(|col| row.get(col).map(|val| (col.clone(), val.clone())))
+                    .collect();
+                serde_json::to_string(&row)?
+            }
+            None => serde_json::to_string(row)?,
+        };
+        out.write_all(line.as_bytes())?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()
+}
+
+/// The Parquet type a column is written as, inferred from every value in it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColumnType {
+    Bool,
+    Int,
+    Double,
+    Str,
+    // nested or mixed values, written as JSON strings
+    Json,
+}
+
+impl ColumnType {
+    fn of(rows: &[Row], column: &str) -> Self {
+        let mut kind = None;
+        for val in rows.iter().filter_map(|row| row.get(column)) {
+            let next = match val {
+                JsonVal::Null => continue,
+                JsonVal::Bool(_) => ColumnType::Bool,
+                JsonVal::Number(num) if num.is_i64() => ColumnType::Int,
+                JsonVal::Number(_) => ColumnType::Double,
+                JsonVal::String(_) => ColumnType::Str,
+                JsonVal::Array(_) | JsonVal::Object(_) => return ColumnType::Json,
+            };
+            kind = match (kind, next) {
+                (None, next) => Some(next),
+                (Some(kind), next) if kind == next => Some(kind),
+                (Some(ColumnType::Int), ColumnType::Double) | (Some(ColumnType::Double), ColumnType::Int) => {
+                    Some(ColumnType::Double)
+                }
+                _ => return ColumnType::Json,
+            };
+        }
+        kind.unwrap_or(ColumnType::Str)
+    }
+
+    fn schema(self, name: &str) -> Result<Type, ParquetError> {
+        let (physical, logical) = match self {
+            ColumnType::Bool => (PhysicalType::BOOLEAN, None),
+            ColumnType::Int => (PhysicalType::INT64, None),
+            ColumnType::Double => (PhysicalType::DOUBLE, None),
+            ColumnType::Str => (PhysicalType::BYTE_ARRAY, Some(LogicalType::String)),
+            ColumnType::Json => (PhysicalType::BYTE_ARRAY, Some(LogicalType::Json)),
+        };
+        Type::primitive_type_builder(name, physical)
+            .with_repetition(Repetition::OPTIONAL)
+            .with_logical_type(logical)
+            .build()
+    }
+}
+
+/// Writes rows as a Parquet file with one optional column per field. A
+/// missing value is written as null.
+fn write_parquet(file: File, rows: &[Row], columns: &[String]) -> io::Result<()> {
+    let kinds: Vec<ColumnType> = columns.iter().map(|col| ColumnType::of(rows, col)).collect();
+    let fields = columns
+        .iter()
+        .zip(&kinds)
+        .map(|(col, kind)| kind.schema(col).map(Arc::new))
+        .collect::<Result<Vec<_>, _>>()?;
+    let schema = Type::group_type_builder("schema").with_fields(fields).build()?;
+    let props = WriterProperties::builder().build();
+    let mut writer = SerializedFileWriter::new(file, Arc::new(schema), Arc::new(props))?;
+    for chunk in rows.chunks(ROW_GROUP_ROWS) {
+        let mut group = writer.next_row_group()?;
+        for (col, kind) in columns.iter().zip(&kinds) {
+            let mut column = group.next_column()?.ok_or_else(|| ParquetError::General("missing column".to_string()))?;
+            let vals: Vec<&JsonVal> = chunk.iter().filter_map(|row| row.get(col)).filter(|val| !val.is_null()).collect();
+            let defs: Vec<i16> = chunk.iter().map(|row| row.get(col).map_or(0, |val| !val.is_null() as i16)).collect();
+            match kind {
+                ColumnType::Bool => {
+                    let vals: Vec<bool> = vals.iter().filter_map(|val| val.as_bool()).collect();
+                    column.typed::<BoolType>().write_batch(&vals, Some(&defs), None)?;
+                }
+                ColumnType::Int => {
+                    let vals: Vec<i64> = vals.iter().filter_map(|val| val.as_i64()).collect();
+                    column.typed::<Int64Type>().write_batch(&vals, Some(&defs), None)?;
+                }
+                ColumnType::Double => {
+                    let vals: Vec<f64> = vals.iter().filter_map(|val| val.as_f64()).collect();
+                    column.typed::<DoubleType>().write_batch(&vals, Some(&defs), None)?;
+                }
+                ColumnType::Str | ColumnType::Json => {
+                    let vals: Vec<ByteArray> = vals
+                        .iter()
+                        .map(|val| match val {
+                            JsonVal::String(s) if *kind == ColumnType::Str => ByteArray::from(s.as_str()),
+                            val => ByteArray::from(val.to_string().into_bytes()),
+                        })
+                        .collect();
+                    column.typed::<ByteArrayType>().write_batch(&vals, Some(&defs), None)?;
+                }
+            }
+            column.close()?;
+        }
+        group.close()?;
+    }
+    writer.close()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, remove_file};
+
+    use super::*;
+    use crate::import::{read_file, ImportOptions};
+
+    #[test]
+    fn write_file_ok() {
+        let rows = vec![
+            obj! {"sym" => "abc", "px" => 1.5, "tags" => JsonVal::from(vec!["a", "b"])},
+            obj! {"sym" => "x,y", "qty" => 3},
+        ];
+        assert_eq!(write_file("./export_a.csv", &rows, &ExportOptions::default()), Ok(2));
+        assert_eq!(
+            fs::read_to_string("./export_a.csv").unwrap(),
+            "px,sym,tags,qty\n1.5,abc,\"[\"\"a\"\",\"\"b\"\"]\",\n,\"x,y\",,3\n"
+        );
+        assert_eq!(write_file("./export_a.csv", &rows, &ExportOptions::default()), Err("export file already exists"));
+
+        let columns = Some(vec!["qty".to_string(), "sym".to_string()]);
+        let opts = ExportOptions { columns, delimiter: Some(';'), header: false, ..ExportOptions::default() };
+        write_file("./export_b.csv", &rows, &opts).unwrap();
+        assert_eq!(fs::read_to_string("./export_b.csv").unwrap(), ";abc\n3;x,y\n");
+
+        // NDJSON round-trips through import
+        write_file("./export_c.ndjson", &rows, &ExportOptions::default()).unwrap();
+        assert_eq!(read_file("./export_c.ndjson", &ImportOptions::default()).unwrap().0, rows);
+        write_file("./export_d.ndjson", &rows, &opts).unwrap();
+        assert_eq!(fs::read_to_string("./export_d.ndjson").unwrap(), "{\"sym\":\"abc\"}\n{\"qty\":3,\"sym\":\"x,y\"}\n");
+
+        remove_file("./export_a.csv").unwrap();
+        remove_file("./export_b.csv").unwrap();
+        remove_file("./export_c.ndjson").unwrap();
+        remove_file("./export_d.ndjson").unwrap();
+    }
+
+    #[test]
+    fn parquet_ok() {
+        let rows = vec![
+            obj! {"sym" => "abc", "px" => 1, "live" => true, "meta" => serde_json::json!({"a": [1, 2]}), "mixed" => 1},
+            obj! {"sym" => "xyz", "px" => 2.5, "mixed" => "one"},
+        ];
+        assert_eq!(write_file("./export_e.parquet", &rows, &ExportOptions::default()), Ok(2));
+        let (read, report) = read_file("./export_e.parquet", &ImportOptions::default()).unwrap();
+        assert_eq!(report.rows, 2);
+        // ints in a column that also holds floats come back as floats
+        assert_eq!(read[0], obj! {"sym" => "abc", "px" => 1.0, "live" => true, "meta" => serde_json::json!({"a": [1, 2]}), "mixed" => 1});
+        assert_eq!(read[1], rows[1]);
+
+        let opts = ExportOptions { columns: Some(vec!["sym".to_string()]), ..ExportOptions::default() };
+        write_file("./export_f.parquet", &rows, &opts).unwrap();
+        let (read, _) = read_file("./export_f.parquet", &ImportOptions::default()).unwrap();
+        assert_eq!(read, vec![obj! {"sym" => "abc"}, obj! {"sym" => "xyz"}]);
+
+        remove_file("./export_e.parquet").unwrap();
+        remove_file("./export_f.parquet").unwrap();
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+
+use parquet::basic::LogicalType;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::Field;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonVal};
+
+use crate::{Res, Row};
+
+/// How many rejected line numbers an import reports; the count covers them all.
+const REJECTED_LINES: usize = 100;
+
+/// The layout of a file rows are imported from or exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FileFormat {
+    #[serde(rename = "csv")]
+    Csv,
+    #[serde(rename = "ndjson")]
+    Ndjson,
+    #[serde(rename = "parquet")]
+    Parquet,
+}
+
+impl FileFormat {
+    /// Guesses the format from the file extension, defaulting to NDJSON.
+    pub fn of<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") || ext.eq_ignore_ascii_case("tsv") => FileFormat::Csv,
+            Some(ext) if ext.eq_ignore_ascii_case("parquet") => FileFormat::Parquet,
+            _ => FileFormat::Ndjson,
+        }
+    }
+}
+
+impl FromStr for FileFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "csv" => Ok(FileFormat::Csv),
+            "ndjson" => Ok(FileFormat::Ndjson),
+            "parquet" => Ok(FileFormat::Parquet),
+            _ => Err("file format must be csv, ndjson or parquet"),
+        }
+    }
+}
+
+/// Options for the `import` command, e.g. `{"format": "csv", "delimiter": ";"}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImportOptions {
+    // guessed from the file extension if not given
+    pub format: Option<FileFormat>,
+    // CSV only: the field separator, a tab for `.tsv` files and `,` otherwise
+    pub delimiter: Option<char>,
+    // CSV only: whether the first line names the columns; without one they
+    // are named `c1`, `c2` and so on
+    pub header: bool,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self { format: None, delimiter: None, header: true }
+    }
+}
+
+/// What an import read: the rows to insert and the lines it had to skip.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ImportReport {
+    pub rows: usize,
+    pub rejected: usize,
+    // the first rejected line numbers, counting from 1
+    pub lines: Vec<u64>,
+}
+
+impl ImportReport {
+    fn reject(&mut self, line: u64) {
+        self.rejected += 1;
+        if self.lines.len() < REJECTED_LINES {
+            self.lines.push(line);
+        }
+    }
+
+    pub fn to_json(&self) -> JsonVal {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+/// Reads the rows of a CSV, NDJSON or Parquet file. Lines that cannot be read
+/// as a row are skipped and reported rather than failing the whole import.
+pub fn read_file<P: AsRef<Path>>(path: P, opts: &ImportOptions) -> Res<(Vec<Row>, ImportReport)> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|err| {
+        eprintln!("cannot open {:?}; error = {:?}", path, err);
+        "cannot open import file"
+    })?;
+    let res = match opts.format.unwrap_or_else(|| FileFormat::of(path)) {
+        FileFormat::Csv => {
+            let tsv = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("tsv"));
+            let delimiter = opts.delimiter.unwrap_or(if tsv { '\t' } else { ',' });
+            if !delimiter.is_ascii() {
+                return Err("delimiter must be a single ascii character");
+            }
+            read_csv(file, delimiter as u8, opts.header)
+        }
+        FileFormat::Ndjson => read_ndjson(file),
+        FileFormat::Parquet => read_parquet(file),
+    };
+    res.map_err(|err| {
+        eprintln!("cannot read {:?}; error = {:?}", path, err);
+        "cannot read import file"
+    })
+}
+
+fn read_csv(file: File, delimiter: u8, header: bool) -> io::Result<(Vec<Row>, ImportReport)> {
+    let mut reader = csv::ReaderBuilder::new().delimiter(delimiter).has_headers(header).from_reader(file);
+    let names: Vec<String> = if header {
+        reader.headers()?.iter().map(str::to_string).collect()
+    } else {
+        Vec::new()
+    };
+    let mut rows = Vec::new();
+    let mut report = ImportReport::default();
+    let mut record = csv::StringRecord::new();
+    loop {
+        match reader.read_record(&mut record) {
+            Ok(true) => {
+                let mut row = Map::new();
+                for (i, field) in record.iter().enumerate() {
+                    let name = names.get(i).cloned().unwrap_or_else(|| format!("c{}", i + 1));
+                    row.insert(name, infer(field));
+                }
+                rows.push(row);
+            }
+            Ok(false) => break,
+            Err(err) => match err.position() {
+                // a record with the wrong number of fields
+                Some(pos) if !err.is_io_error() => report.reject(pos.line()),
+                _ => return Err(err.into()),
+            },
+        }
+    }
+    report.rows = rows.len();
+    Ok((rows, report))
+}
+
+/// Types a CSV field: numbers and booleans are read as such, an empty field
+/// as `null` and anything else as a string.
+fn infer(field: &str) -> JsonVal {
+    if field.is_empty() {
+        return JsonVal::Null;
+    }
+    match serde_json::from_str(field) {
+        Ok(val @ JsonVal::Number(_)) | Ok(val @ JsonVal::Bool(_)) => val,
+        _ => JsonVal::from(field),
+    }
+}
+
+fn read_ndjson(file: File) -> io::Result<(Vec<Row>, ImportReport)> {
+    let mut rows = Vec::new();
+    let mut report = ImportReport::default();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(JsonVal::Object(row)) => rows.push(row),
+            _ => report.reject(i as u64 + 1),
+        }
+    }
+    report.rows = rows.len();
+    Ok((rows, report))
+}
+
+/// Reads every row of a Parquet file. Null values are left out of the rows,
+/// and columns written as JSON are parsed back into the values they hold.
+fn read_parquet(file: File) -> io::Result<(Vec<Row>, ImportReport)> {
+    let reader = SerializedFileReader::new(file)?;
+    let json: BTreeSet<String> = reader
+        .metadata()
+        .file_metadata()
+        .schema_descr()
+        .columns()
+        .iter()
+        .filter(|col| col.logical_type() == Some(LogicalType::Json))
+        .map(|col| col.name().to_string())
+        .collect();
+    let mut rows = Vec::new();
+    for record in reader.get_row_iter(None)? {
+        let mut row = Map::new();
+        for (name, field) in record?.get_column_iter() {
+            let val = match field {
+                Field::Null => continue,
+                Field::Str(s) if json.contains(name) => serde_json::from_str(s).unwrap_or_else(|_| JsonVal::from(s.as_str())),
+                field => field_json(field),
+            };
+            row.insert(name.clone(), val);
+        }
+        rows.push(row);
+    }
+    let report = ImportReport { rows: rows.len(), ..ImportReport::default() };
+    Ok((rows, report))
+}
+
+/// Converts a Parquet value to JSON. Dates are read as days and timestamps
+/// as milliseconds since the unix epoch; values with no JSON counterpart,
+/// such as decimals and nested groups, are read as their text.
+fn field_json(field: &Field) -> JsonVal {
+    match *field {
+        Field::Bool(b) => JsonVal::from(b),
+        Field::Byte(n) => JsonVal::from(n),
+        Field::Short(n) => JsonVal::from(n),
+        Field::Int(n) => JsonVal::from(n),
+        Field::Long(n) => JsonVal::from(n),
+        Field::UByte(n) => JsonVal::from(n),
+        Field::UShort(n) => JsonVal::from(n),
+        Field::UInt(n) => JsonVal::from(n),
+        Field::ULong(n) => JsonVal::from(n),
+        Field::Float(n) => JsonVal::from(n),
+        Field::Double(n) => JsonVal::from(n),
+        Field::Str(ref s) => JsonVal::from(s.as_str()),
+        Field::Date(days) => JsonVal::from(days),
+        Field::TimestampMillis(ms) => JsonVal::from(ms),
+        Field::TimestampMicros(us) => JsonVal::from(us / 1000),
+        ref field => JsonVal::from(field.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, remove_file};
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn read_csv_ok() {
+        fs::write("./import_a.csv", "sym;px;qty;live;note\nabc;1.5;10;true;\nxyz;2\n007;3;-1;false;\"a;b\"\n").unwrap();
+        let opts = ImportOptions { delimiter: Some(';'), ..ImportOptions::default() };
+        let (rows, report) = read_file("./import_a.csv", &opts).unwrap();
+        assert_eq!(report, ImportReport { rows: 2, rejected: 1, lines: vec![3] });
+        assert_eq!(JsonVal::from(rows[0].clone()), json!({"sym": "abc", "px": 1.5, "qty": 10, "live": true, "note": null}));
+        assert_eq!(JsonVal::from(rows[1].clone()), json!({"sym": "007", "px": 3, "qty": -1, "live": false, "note": "a;b"}));
+
+        let opts = ImportOptions { header: false, format: Some(FileFormat::Csv), ..ImportOptions::default() };
+        fs::write("./import_a.txt", "1,x\n2,y\n").unwrap();
+        let (rows, _) = read_file("./import_a.txt", &opts).unwrap();
+        assert_eq!(JsonVal::from(rows[1].clone()), json!({"c1": 2, "c2": "y"}));
+
+        remove_file("./import_a.csv").unwrap();
+        remove_file("./import_a.txt").unwrap();
+    }
+
+    #[test]
+    fn read_ndjson_ok() {
+        fs::write("./import_b.ndjson", "{\"x\": 1}\n\n[1]\n{\"x\": {\"y\": 2}}\n{bad\n").unwrap();
+        let (rows, report) = read_file("./import_b.ndjson", &ImportOptions::default()).unwrap();
+        assert_eq!(rows, vec![obj! {"x" => 1}, obj! {"x" => json!({"y": 2})}]);
+        assert_eq!(report, ImportReport { rows: 2, rejected: 2, lines: vec![3, 5] });
+        assert_eq!(read_file("./missing.ndjson", &ImportOptions::default()), Err("cannot open import file"));
+
+        remove_file("./import_b.ndjson").unwrap();
+    }
+}
@@ -1,9 +1,15 @@
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value as JsonVal};
 use serde_json::Number as JsonNum;
 use serde_json::Number;
 
-use crate::db::Database;
+use crate::db::{Database, Partition, Retention};
+use crate::export::{ExportOptions, ExportSource};
+use crate::import::ImportOptions;
+use crate::query::Query;
+use crate::Row;
 
 pub type Res<T> = Result<T, &'static str>;
 
@@ -19,6 +25,14 @@ const BAD_JSON: &str = "bad json";
 
 const BAD_NUM: &str = "bad number";
 
+const BAD_CMD: &str = "bad command";
+
+/// The most seconds a ttl or retention may span, so that it still fits in
+/// milliseconds as an `i64`.
+pub const MAX_SECS: u64 = i64::MAX as u64 / 1000;
+
+pub const BAD_BATCH: &str = "backup, export, import and query cannot be run in a batch";
+
 pub fn json_first(val: &JsonVal) -> Res<JsonVal> {
     match val {
         JsonVal::Number(val) => Ok(JsonVal::Number(val.clone())),
@@ -425,7 +439,7 @@ fn arr_min(s: &[JsonVal]) -> Res<Option<JsonVal>> {
     Ok(Some(JsonVal::from(min)))
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum Cmd {
     #[serde(rename = "get")]
     Get(String),
@@ -458,6 +472,124 @@ pub enum Cmd {
     Mul(Box<Cmd>, Box<Cmd>),
     #[serde(rename = "/")]
     Div(Box<Cmd>, Box<Cmd>),
+    #[serde(rename = "setex")]
+    SetEx(String, JsonVal, u64),
+    #[serde(rename = "expire")]
+    Expire(String, u64),
+    #[serde(rename = "ttl")]
+    Ttl(String),
+    #[serde(rename = "persist")]
+    Persist(String),
+    #[serde(rename = "getv")]
+    GetV(String),
+    #[serde(rename = "cas")]
+    Cas(String, Option<u64>, JsonVal),
+    #[serde(rename = "insert")]
+    Insert(String, Vec<Row>),
+    #[serde(rename = "retention")]
+    Retention(String, Option<Retention>),
+    #[serde(rename = "snapshot")]
+    Snapshot(Option<String>),
+    #[serde(rename = "rename")]
+    Rename(String, String),
+    #[serde(rename = "truncate")]
+    Truncate(String),
+    #[serde(rename = "clone")]
+    Clone(String, String),
+    #[serde(rename = "backup")]
+    Backup(String),
+    #[serde(rename = "stats")]
+    Stats,
+    #[serde(rename = "import")]
+    Import(String, String, ImportOptions),
+    #[serde(rename = "export")]
+    Export(ExportSource, String, ExportOptions),
+    #[serde(rename = "query")]
+    Query(Query),
+    #[serde(rename = "partition")]
+    Partition(String, Partition),
+    #[serde(rename = "partitions")]
+    Partitions(String),
+    #[serde(rename = "drop_partitions")]
+    DropPartitions(String, String),
+    #[serde(rename = "archive_partitions")]
+    ArchivePartitions(String, String, String),
+    Batch(Vec<Cmd>),
+    #[serde(rename = "multi")]
+    Multi,
+    #[serde(rename = "exec")]
+    Exec,
+    #[serde(rename = "discard")]
+    Discard,
+    #[serde(rename = "watch")]
+    Watch(Vec<String>),
+    #[serde(rename = "unwatch")]
+    Unwatch,
+}
+
+impl Cmd {
+    /// Whether evaluating the command needs exclusive access to the database.
+    pub fn is_write(&self) -> bool {
+        match self {
+            Cmd::Set(_, _)
+            | Cmd::SetEx(_, _, _)
+            | Cmd::Del(_)
+            | Cmd::Expire(_, _)
+            | Cmd::Persist(_)
+            | Cmd::Cas(_, _, _)
+            | Cmd::Insert(_, _)
+            | Cmd::Retention(_, _)
+            | Cmd::Snapshot(_)
+            | Cmd::Rename(_, _)
+            | Cmd::Truncate(_)
+            | Cmd::Clone(_, _)
+            | Cmd::Import(_, _, _)
+            | Cmd::Partition(_, _)
+            | Cmd::DropPartitions(_, _)
+            | Cmd::ArchivePartitions(_, _, _)
+            // load the tables they read if they are cold
+            | Cmd::Export(_, _, _)
+            | Cmd::Query(_) => true,
+            Cmd::Batch(cmds) => cmds.iter().any(Cmd::is_write),
+            _ => false,
+        }
+    }
+
+    /// Whether the command leaves the data unchanged. Queries and exports
+    /// may still load cold tables, so they are not all reads to `is_write`.
+    pub fn is_read(&self) -> bool {
+        match self {
+            Cmd::Export(_, _, _) | Cmd::Query(_) => true,
+            Cmd::Multi | Cmd::Exec | Cmd::Discard | Cmd::Watch(_) | Cmd::Unwatch => false,
+            cmd => !cmd.is_write(),
+        }
+    }
+
+    /// Whether the command can be run inside a batch. Backups, imports,
+    /// exports and queries take the lock in steps of their own, so they
+    /// are only run on their own.
+    pub fn batchable(&self) -> bool {
+        match self {
+            Cmd::Backup(_) | Cmd::Import(_, _, _) | Cmd::Export(_, _, _) | Cmd::Query(_) => false,
+            Cmd::Batch(cmds) => cmds.iter().all(Cmd::batchable),
+            _ => true,
+        }
+    }
+
+    /// Whether the command may add data, and so is refused once memory is
+    /// over `maxmemory` and nothing can be evicted.
+    pub fn may_grow(&self) -> bool {
+        match self {
+            Cmd::Set(_, _)
+            | Cmd::SetEx(_, _, _)
+            | Cmd::Cas(_, _, _)
+            | Cmd::Insert(_, _)
+            | Cmd::Clone(_, _)
+            | Cmd::Import(_, _, _) => true,
+            Cmd::Batch(cmds) => cmds.iter().any(Cmd::may_grow),
+            _ => false,
+        }
+    }
 }
 
 pub fn parse_json_str<S: Into<String>>(s: S) -> Res<Cmd> {
@@ -465,7 +597,7 @@ pub fn parse_json_str<S: Into<String>>(s: S) -> Res<Cmd> {
     parse_json_val(json_val)
 }
 
-fn parse_json_val(val: JsonVal) -> Res<Cmd> {
+pub fn parse_json_val(val: JsonVal) -> Res<Cmd> {
     match val {
         JsonVal::Object(obj) => parse_obj(obj),
         val => Ok(Cmd::Val(val)),
@@ -473,30 +605,55 @@ fn parse_json_val(val: JsonVal) -> Res<Cmd> {
 }
 
 fn parse_obj(obj: Map<String, JsonVal>) -> Res<Cmd> {
-    if obj.len() != 1 {
-        return Err("not one key");
-    }
-    for (key, val) in obj {
-        match key.as_ref() {
-            "get" => return parse_get(val),
-            "del" => return parse_del(val),
-            "set" => return parse_set(val),
-            "min" => return parse_min(val),
-            "max" => return parse_max(val),
-            "sum" => return parse_sum(val),
-            "avg" => return parse_avg(val),
-            "var" => return parse_var(val),
-            "dev" => return parse_dev(val),
-            "first" => return parse_first(val),
-            "last" => return parse_last(val),
-            "+" => return parse_add(val),
-            "-" => return parse_sub(val),
-            "*" => return parse_mul(val),
-            "/" => return parse_div(val),
-            _ => unimplemented!(),
-        }
-    }
-    Ok(Cmd::Set("k1".to_string(), JsonVal::Bool(true)))
+    // a command is an object with exactly one key, naming the command
+    let mut entries = obj.into_iter();
+    let (key, val) = match (entries.next(), entries.next()) {
+        (Some(entry), None) => entry,
+        _ => return Err(BAD_CMD),
+    };
+    match key.as_ref() {
+        "get" => parse_get(val),
+        "del" => parse_del(val),
+        "set" => parse_set(val),
+        "min" => parse_min(val),
+        "max" => parse_max(val),
+        "sum" => parse_sum(val),
+        "avg" => parse_avg(val),
+        "var" => parse_var(val),
+        "dev" => parse_dev(val),
+        "first" => parse_first(val),
+        "last" => parse_last(val),
+        "+" => parse_add(val),
+        "-" => parse_sub(val),
+        "*" => parse_mul(val),
+        "/" => parse_div(val),
+        "expire" => parse_expire(val),
+        "ttl" => parse_key(val).map(Cmd::Ttl),
+        "persist" => parse_key(val).map(Cmd::Persist),
+        "getv" => parse_getv(val),
+        "cas" => parse_cas(val),
+        "insert" => parse_insert(val),
+        "retention" => parse_retention(val),
+        "snapshot" => parse_snapshot(val),
+        "rename" => parse_tables(val).map(|(from, to)| Cmd::Rename(from, to)),
+        "truncate" => Ok(Cmd::Truncate(parse_key(val)?)),
+        "clone" => parse_tables(val).map(|(from, to)| Cmd::Clone(from, to)),
+        "backup" => Ok(Cmd::Backup(parse_key(val)?)),
+        "stats" => Ok(Cmd::Stats),
+        "import" => parse_import(val),
+        "export" => parse_export(val),
+        "query" => serde_json::from_value(val).map(Cmd::Query).map_err(|_| BAD_TYPE),
+        "partition" => parse_partition(val),
+        "partitions" => Ok(Cmd::Partitions(parse_key(val)?)),
+        "drop_partitions" => parse_tables(val).map(|(table, before)| Cmd::DropPartitions(table, before)),
+        "archive_partitions" => parse_archive(val),
+        "multi" => Ok(Cmd::Multi),
+        "exec" => Ok(Cmd::Exec),
+        "discard" => Ok(Cmd::Discard),
+        "watch" => parse_watch(val),
+        "unwatch" => Ok(Cmd::Unwatch),
+        _ => Err(BAD_CMD),
+    }
 }
 
 fn parse_min(val: JsonVal) -> Res<Cmd> {
@@ -555,19 +712,227 @@ fn parse_del(val: JsonVal) -> Res<Cmd> {
     }
 }
 
+/// Parses `["key", value]`, or `["key", value, {"ttl": seconds}]` to expire
+/// the key.
 fn parse_set(val: JsonVal) -> Res<Cmd> {
     match val {
-        JsonVal::Array(mut arr) => {
+        JsonVal::Array(mut arr) if arr.len() == 2 || arr.len() == 3 => {
+            let ttl = if arr.len() == 3 {
+                match arr.remove(2) {
+                    JsonVal::Object(opts) => match opts.get("ttl") {
+                        Some(JsonVal::Number(secs)) => Some(secs.as_u64().filter(|secs| *secs <= MAX_SECS).ok_or(BAD_NUM)?),
+                        _ => return Err(BAD_TYPE),
+                    },
+                    _ => return Err(BAD_TYPE),
+                }
+            } else {
+                None
+            };
             let val = arr.remove(1);
             let key = arr.remove(0);
             let key = match key {
                 JsonVal::String(key) => key,
-                _ => unimplemented!(),
+                _ => return Err(BAD_KEY),
+            };
+            match ttl {
+                Some(secs) => Ok(Cmd::SetEx(key, val, secs)),
+                None => Ok(Cmd::Set(key, val)),
+            }
+        }
+        _ => Err(BAD_TYPE),
+    }
+}
+
+fn parse_key(val: JsonVal) -> Res<String> {
+    match val {
+        JsonVal::String(key) => Ok(key),
+        _ => Err(BAD_KEY),
+    }
+}
+
+/// Parses `["key", seconds]`.
+fn parse_expire(val: JsonVal) -> Res<Cmd> {
+    match val {
+        JsonVal::Array(mut arr) if arr.len() == 2 => {
+            let secs = arr.remove(1).as_u64().filter(|secs| *secs <= MAX_SECS).ok_or(BAD_NUM)?;
+            Ok(Cmd::Expire(parse_key(arr.remove(0))?, secs))
+        }
+        _ => Err(BAD_TYPE),
+    }
+}
+
+fn parse_getv(val: JsonVal) -> Res<Cmd> {
+    match val {
+        JsonVal::String(key) => Ok(Cmd::GetV(key)),
+        _ => Err(BAD_TYPE),
+    }
+}
+
+/// Parses `["key", version, value]`, where a `null` version means the key
+/// must not exist yet.
+fn parse_cas(val: JsonVal) -> Res<Cmd> {
+    match val {
+        JsonVal::Array(mut arr) if arr.len() == 3 => {
+            let val = arr.remove(2);
+            let version = match arr.remove(1) {
+                JsonVal::Null => None,
+                JsonVal::Number(num) => Some(num.as_u64().ok_or(BAD_NUM)?),
+                _ => return Err(BAD_TYPE),
+            };
+            match arr.remove(0) {
+                JsonVal::String(key) => Ok(Cmd::Cas(key, version, val)),
+                _ => Err(BAD_KEY),
+            }
+        }
+        _ => Err(BAD_TYPE),
+    }
+}
+
+/// Parses a batch of commands, e.g. `[{"set": ["a", 1]}, {"set": ["b", 2]}]`.
+pub fn parse_batch(arr: Vec<JsonVal>) -> Res<Cmd> {
+    let mut cmds = Vec::with_capacity(arr.len());
+    for val in arr {
+        cmds.push(parse_json_val(val)?);
+    }
+    Ok(Cmd::Batch(cmds))
+}
+
+fn parse_insert(val: JsonVal) -> Res<Cmd> {
+    match val {
+        JsonVal::Array(mut arr) if arr.len() == 2 => {
+            let rows = match arr.remove(1) {
+                JsonVal::Object(row) => vec![row],
+                JsonVal::Array(vals) => {
+                    let mut rows = Vec::with_capacity(vals.len());
+                    for val in vals {
+                        match val {
+                            JsonVal::Object(row) => rows.push(row),
+                            _ => return Err(BAD_TYPE),
+                        }
+                    }
+                    rows
+                }
+                _ => return Err(BAD_TYPE),
+            };
+            match arr.remove(0) {
+                JsonVal::String(table) => Ok(Cmd::Insert(table, rows)),
+                _ => Err(BAD_KEY),
+            }
+        }
+        _ => Err(BAD_TYPE),
+    }
+}
+
+/// Parses `["table", policy]`, where a `null` policy keeps every row.
+fn parse_retention(val: JsonVal) -> Res<Cmd> {
+    match val {
+        JsonVal::Array(mut arr) if arr.len() == 2 => {
+            let retention = serde_json::from_value(arr.remove(1)).map_err(|_| BAD_TYPE)?;
+            if let Some(Retention::MaxAge { secs, .. }) = retention {
+                if secs > MAX_SECS {
+                    return Err(BAD_NUM);
+                }
+            }
+            Ok(Cmd::Retention(parse_key(arr.remove(0))?, retention))
+        }
+        _ => Err(BAD_TYPE),
+    }
+}
+
+/// Parses a table name, or `null` for every table.
+fn parse_snapshot(val: JsonVal) -> Res<Cmd> {
+    match val {
+        JsonVal::Null => Ok(Cmd::Snapshot(None)),
+        val => Ok(Cmd::Snapshot(Some(parse_key(val)?))),
+    }
+}
+
+/// Parses a `["from", "to"]` pair of table names.
+fn parse_tables(val: JsonVal) -> Res<(String, String)> {
+    match val {
+        JsonVal::Array(mut arr) if arr.len() == 2 => {
+            let to = parse_key(arr.remove(1))?;
+            Ok((parse_key(arr.remove(0))?, to))
+        }
+        _ => Err(BAD_TYPE),
+    }
+}
+
+/// Parses `["table", "path"]`, or `["table", "path", options]`.
+fn parse_import(val: JsonVal) -> Res<Cmd> {
+    match val {
+        JsonVal::Array(mut arr) if arr.len() == 2 || arr.len() == 3 => {
+            let opts = match arr.len() {
+                3 => serde_json::from_value(arr.remove(2)).map_err(|_| BAD_TYPE)?,
+                _ => ImportOptions::default(),
             };
-            Ok(Cmd::Set(key, val))
+            let path = parse_key(arr.remove(1))?;
+            Ok(Cmd::Import(parse_key(arr.remove(0))?, path, opts))
         }
-        JsonVal::Object(_obj) => unimplemented!(),
-        _ => unimplemented!(),
+        _ => Err(BAD_TYPE),
+    }
+}
+
+/// Parses `[source, "path"]`, or `[source, "path", options]`, where the
+/// source is a table name or a query.
+fn parse_export(val: JsonVal) -> Res<Cmd> {
+    match val {
+        JsonVal::Array(mut arr) if arr.len() == 2 || arr.len() == 3 => {
+            let opts = match arr.len() {
+                3 => serde_json::from_value(arr.remove(2)).map_err(|_| BAD_TYPE)?,
+                _ => ExportOptions::default(),
+            };
+            let path = parse_key(arr.remove(1))?;
+            let source = match arr.remove(0) {
+                JsonVal::String(table) => ExportSource::Table(table),
+                JsonVal::Object(qry) => {
+                    ExportSource::Query(serde_json::from_value(JsonVal::Object(qry)).map_err(|_| BAD_TYPE)?)
+                }
+                _ => return Err(BAD_KEY),
+            };
+            Ok(Cmd::Export(source, path, opts))
+        }
+        _ => Err(BAD_TYPE),
+    }
+}
+
+/// Parses `["table", {"field": "time"}]`.
+fn parse_partition(val: JsonVal) -> Res<Cmd> {
+    match val {
+        JsonVal::Array(mut arr) if arr.len() == 2 => {
+            let partition = serde_json::from_value(arr.remove(1)).map_err(|_| BAD_TYPE)?;
+            Ok(Cmd::Partition(parse_key(arr.remove(0))?, partition))
+        }
+        _ => Err(BAD_TYPE),
+    }
+}
+
+/// Parses `["table", "YYYY-MM-DD", "dir"]`.
+fn parse_archive(val: JsonVal) -> Res<Cmd> {
+    match val {
+        JsonVal::Array(mut arr) if arr.len() == 3 => {
+            let dir = parse_key(arr.remove(2))?;
+            let before = parse_key(arr.remove(1))?;
+            Ok(Cmd::ArchivePartitions(parse_key(arr.remove(0))?, before, dir))
+        }
+        _ => Err(BAD_TYPE),
+    }
+}
+
+fn parse_watch(val: JsonVal) -> Res<Cmd> {
+    match val {
+        JsonVal::String(key) => Ok(Cmd::Watch(vec![key])),
+        JsonVal::Array(arr) => {
+            let mut keys = Vec::with_capacity(arr.len());
+            for key in arr {
+                match key {
+                    JsonVal::String(key) => keys.push(key),
+                    _ => return Err(BAD_KEY),
+                }
+            }
+            Ok(Cmd::Watch(keys))
+        }
+        _ => Err(BAD_KEY),
     }
 }
 
@@ -587,53 +952,165 @@ fn parse_last(val: JsonVal) -> Res<Cmd> {
 
 fn parse_add(val: JsonVal) -> Res<Cmd> {
     match val {
-        JsonVal::Array(mut arr) => {
+        JsonVal::Array(mut arr) if arr.len() == 2 => {
             let rhs = parse_json_val(arr.remove(1))?;
             let lhs = parse_json_val(arr.remove(0))?;
             Ok(Cmd::Add(Box::new(lhs), Box::new(rhs)))
         }
-        _ => unimplemented!(),
+        _ => Err(BAD_TYPE),
     }
 }
 
 fn parse_sub(val: JsonVal) -> Res<Cmd> {
     match val {
-        JsonVal::Array(mut arr) => {
+        JsonVal::Array(mut arr) if arr.len() == 2 => {
             let rhs = parse_json_val(arr.remove(1))?;
             let lhs = parse_json_val(arr.remove(0))?;
             Ok(Cmd::Sub(Box::new(lhs), Box::new(rhs)))
         }
-        _ => unimplemented!(),
+        _ => Err(BAD_TYPE),
     }
 }
 
 fn parse_mul(val: JsonVal) -> Res<Cmd> {
     match val {
-        JsonVal::Array(mut arr) => {
+        JsonVal::Array(mut arr) if arr.len() == 2 => {
             let rhs = parse_json_val(arr.remove(1))?;
             let lhs = parse_json_val(arr.remove(0))?;
             Ok(Cmd::Mul(Box::new(lhs), Box::new(rhs)))
         }
-        _ => unimplemented!(),
+        _ => Err(BAD_TYPE),
     }
 }
 
 fn parse_div(val: JsonVal) -> Res<Cmd> {
     match val {
-        JsonVal::Array(mut arr) => {
+        JsonVal::Array(mut arr) if arr.len() == 2 => {
             let rhs = parse_json_val(arr.remove(1))?;
             let lhs = parse_json_val(arr.remove(0))?;
             Ok(Cmd::Div(Box::new(lhs), Box::new(rhs)))
         }
-        _ => unimplemented!(),
+        _ => Err(BAD_TYPE),
     }
 }
 
+/// Evaluates a command that may mutate the database.
 pub fn eval_json_cmd(cmd: Cmd, db: &mut Database) -> Res<JsonVal> {
     match cmd {
-        Cmd::Get(ref key) => unimplemented!(),
-        Cmd::Del(ref key) => unimplemented!(),
-        Cmd::Set(key, val) => db_write(db, key, val),
+        Cmd::Set(key, val) => {
+            let prev = db.set(key, val).map_err(|_| BAD_IO)?;
+            Ok(prev.unwrap_or(JsonVal::Null))
+        }
+        Cmd::SetEx(key, val, secs) => {
+            let prev = db.set_ex(key, val, Some(Duration::from_secs(secs))).map_err(|_| BAD_IO)?;
+            Ok(prev.unwrap_or(JsonVal::Null))
+        }
+        Cmd::Del(ref key) => {
+            let prev = db.del(key).map_err(|_| BAD_IO)?;
+            Ok(prev.unwrap_or(JsonVal::Null))
+        }
+        Cmd::Expire(ref key, secs) => {
+            let found = db.expire(key, Duration::from_secs(secs)).map_err(|_| BAD_IO)?;
+            Ok(JsonVal::from(found))
+        }
+        Cmd::Persist(ref key) => Ok(JsonVal::from(db.persist(key).map_err(|_| BAD_IO)?)),
+        Cmd::Cas(key, version, val) => Ok(JsonVal::from(db.cas(key, version, val)?)),
+        Cmd::Insert(table, rows) => {
+            let n = rows.len();
+            db.insert_table(table, rows)?;
+            Ok(JsonVal::from(n))
+        }
+        Cmd::Retention(ref table, retention) => {
+            db.set_retention(table, retention)?;
+            Ok(JsonVal::from("OK"))
+        }
+        Cmd::Snapshot(ref table) => Ok(JsonVal::from(db.snapshot(table.as_ref().map(String::as_str))?)),
+        Cmd::Rename(ref from, ref to) => {
+            db.rename_table(from, to)?;
+            Ok(JsonVal::from("OK"))
+        }
+        Cmd::Truncate(ref table) => Ok(JsonVal::from(db.truncate_table(table)?)),
+        Cmd::Clone(ref from, ref to) => Ok(JsonVal::from(db.clone_table(from, to)?)),
+        Cmd::Import(table, ref path, ref opts) => {
+            let path = db.import_path(path)?;
+            Ok(db.import(table, path, opts)?.to_json())
+        }
+        Cmd::Export(ref source, ref path, ref opts) => {
+            let path = db.export_path(path)?;
+            Ok(JsonVal::from(db.export(source, path, opts)?))
+        }
+        Cmd::Query(ref qry) => Ok(JsonVal::from(qry.exec(db)?)),
+        Cmd::Partition(ref table, partition) => {
+            db.set_partition(table, partition)?;
+            Ok(JsonVal::from("OK"))
+        }
+        Cmd::DropPartitions(ref table, ref before) => Ok(JsonVal::from(db.drop_partitions(table, before)?)),
+        Cmd::ArchivePartitions(ref table, ref before, ref dir) => {
+            // archives are exports, so they go in the export directory
+            let dir = db.export_path(dir)?;
+            Ok(JsonVal::from(db.archive_partitions(table, before, dir)?))
+        }
+        Cmd::Batch(cmds) => {
+            if !cmds.iter().all(Cmd::batchable) {
+                return Err(BAD_BATCH);
+            }
+            Ok(JsonVal::from(db.eval_batch(cmds)?))
+        }
+        cmd => eval_json_query(cmd, db),
+    }
+}
+
+/// Evaluates a read-only command; writes nested inside it are rejected.
+pub fn eval_json_query(cmd: Cmd, db: &Database) -> Res<JsonVal> {
+    match cmd {
+        Cmd::Get(ref key) => Ok(db.get(key).cloned().unwrap_or(JsonVal::Null)),
+        Cmd::GetV(ref key) => match db.get_entry(key) {
+            Some(entry) => {
+                let mut obj = Map::new();
+                obj.insert("value".to_string(), entry.val().clone());
+                obj.insert("version".to_string(), JsonVal::from(entry.version()));
+                Ok(JsonVal::Object(obj))
+            }
+            None => Ok(JsonVal::Null),
+        },
+        // like redis: -2 if the key does not exist, -1 if it never expires
+        Cmd::Ttl(ref key) => Ok(match db.ttl(key) {
+            None => JsonVal::from(-2),
+            Some(None) => JsonVal::from(-1),
+            Some(Some(ttl)) => JsonVal::from((ttl.as_millis() as u64).div_ceil(1000)),
+        }),
+        Cmd::Set(_, _)
+        | Cmd::SetEx(_, _, _)
+        | Cmd::Del(_)
+        | Cmd::Expire(_, _)
+        | Cmd::Persist(_)
+        | Cmd::Cas(_, _, _)
+        | Cmd::Insert(_, _)
+        | Cmd::Retention(_, _)
+        | Cmd::Snapshot(_)
+        | Cmd::Rename(_, _)
+        | Cmd::Truncate(_)
+        | Cmd::Clone(_, _)
+        | Cmd::Import(_, _, _)
+        | Cmd::Export(_, _, _)
+        | Cmd::Query(_)
+        | Cmd::Partition(_, _)
+        | Cmd::DropPartitions(_, _)
+        | Cmd::ArchivePartitions(_, _, _) => Err(BAD_WRITE),
+        Cmd::Partitions(ref table) => Ok(JsonVal::from(db.partition_dates(table)?)),
+        Cmd::Backup(ref dir) => Ok(JsonVal::from(db.backup(db.backup_path(dir)?)?)),
+        Cmd::Stats => Ok(db.stats()),
+        Cmd::Batch(cmds) => {
+            if !cmds.iter().all(Cmd::batchable) {
+                return Err(BAD_BATCH);
+            }
+            let mut vals = Vec::with_capacity(cmds.len());
+            for cmd in cmds {
+                vals.push(eval_json_query(cmd, db)?);
+            }
+            Ok(JsonVal::from(vals))
+        }
+        Cmd::Multi | Cmd::Exec | Cmd::Discard | Cmd::Watch(_) | Cmd::Unwatch => Err(BAD_CMD),
         Cmd::Sum(arg) => eval_sum(*arg, db),
         Cmd::Min(arg) => eval_min(*arg, db),
         Cmd::Max(arg) => eval_max(*arg, db),
@@ -650,85 +1127,81 @@ pub fn eval_json_cmd(cmd: Cmd, db: &mut Database) -> Res<JsonVal> {
     }
 }
 
-fn db_write(db: &mut Database, table: String, val: JsonVal) -> Res<JsonVal> {
-    unimplemented!()
-}
-
-fn eval_sum(arg: Cmd, db: &mut Database) -> Res<JsonVal> {
-    match eval_json_cmd(arg, db) {
+fn eval_sum(arg: Cmd, db: &Database) -> Res<JsonVal> {
+    match eval_json_query(arg, db) {
         Ok(ref val) => json_sum(val),
         Err(err) => Err(err),
     }
 }
 
-fn eval_avg(arg: Cmd, db: &mut Database) -> Res<JsonVal> {
-    match eval_json_cmd(arg, db) {
+fn eval_avg(arg: Cmd, db: &Database) -> Res<JsonVal> {
+    match eval_json_query(arg, db) {
         Ok(ref val) => json_avg(val),
         Err(err) => Err(err),
     }
 }
 
-fn eval_dev(arg: Cmd, db: &mut Database) -> Res<JsonVal> {
-    match eval_json_cmd(arg, db) {
+fn eval_dev(arg: Cmd, db: &Database) -> Res<JsonVal> {
+    match eval_json_query(arg, db) {
         Ok(ref val) => json_dev(val),
         Err(err) => Err(err),
     }
 }
 
-fn eval_var(arg: Cmd, db: &mut Database) -> Res<JsonVal> {
-    match eval_json_cmd(arg, db) {
+fn eval_var(arg: Cmd, db: &Database) -> Res<JsonVal> {
+    match eval_json_query(arg, db) {
         Ok(ref val) => json_var(val),
         Err(err) => Err(err),
     }
 }
 
-fn eval_first(arg: Cmd, db: &mut Database) -> Res<JsonVal> {
-    match eval_json_cmd(arg, db) {
+fn eval_first(arg: Cmd, db: &Database) -> Res<JsonVal> {
+    match eval_json_query(arg, db) {
         Ok(ref val) => json_first(val),
         Err(err) => Err(err),
     }
 }
 
-fn eval_add(lhs: Cmd, rhs: Cmd, db: &mut Database) -> Res<JsonVal> {
-    let x = eval_json_cmd(lhs, db)?;
-    let y = eval_json_cmd(rhs, db)?;
+fn eval_add(lhs: Cmd, rhs: Cmd, db: &Database) -> Res<JsonVal> {
+    let x = eval_json_query(lhs, db)?;
+    let y = eval_json_query(rhs, db)?;
     json_add(&x, &y)
 }
 
-fn eval_sub(lhs: Cmd, rhs: Cmd, db: &mut Database) -> Res<JsonVal> {
-    let x = eval_json_cmd(lhs, db)?;
-    let y = eval_json_cmd(rhs, db)?;
+fn eval_sub(lhs: Cmd, rhs: Cmd, db: &Database) -> Res<JsonVal> {
+    let x = eval_json_query(lhs, db)?;
+    let y = eval_json_query(rhs, db)?;
     json_sub(&x, &y)
 }
 
-fn eval_mul(lhs: Cmd, rhs: Cmd, db: &mut Database) -> Res<JsonVal> {
-    let x = eval_json_cmd(lhs, db)?;
-    let y = eval_json_cmd(rhs, db)?;
+fn eval_mul(lhs: Cmd, rhs: Cmd, db: &Database) -> Res<JsonVal> {
+    let x = eval_json_query(lhs, db)?;
+    let y = eval_json_query(rhs, db)?;
     json_mul(&x, &y)
 }
 
-fn eval_div(lhs: Cmd, rhs: Cmd, db: &mut Database) -> Res<JsonVal> {
-    let x = eval_json_cmd(lhs, db)?;
-    let y = eval_json_cmd(rhs, db)?;
+fn eval_div(lhs: Cmd, rhs: Cmd, db: &Database) -> Res<JsonVal> {
+    let x = eval_json_query(lhs, db)?;
+    let y = eval_json_query(rhs, db)?;
     json_div(&x, &y)
 }
 
-fn eval_last(arg: Cmd, db: &mut Database) -> Res<JsonVal> {
-    match eval_json_cmd(arg, db) {
+fn eval_last(arg: Cmd, db: &Database) -> Res<JsonVal> {
+    match eval_json_query(arg, db) {
         Ok(ref val) => json_last(val),
         Err(err) => Err(err),
     }
 }
 
-fn eval_max(arg: Cmd, db: &mut Database) -> Res<JsonVal> {
-    match eval_json_cmd(arg, db) {
+fn eval_max(arg: Cmd, db: &Database) -> Res<JsonVal> {
+    match eval_json_query(arg, db) {
         Ok(ref val) => json_max(val),
         Err(err) => Err(err),
     }
 }
 
-fn eval_min(arg: Cmd, db: &mut Database) -> Res<JsonVal> {
-    match eval_json_cmd(arg, db) {
+fn eval_min(arg: Cmd, db: &Database) -> Res<JsonVal> {
+    match eval_json_query(arg, db) {
         Ok(ref val) => json_min(val),
         Err(err) => Err(err),
     }
@@ -1,11 +1,18 @@
+use std::collections::BTreeMap;
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
-use std::path::{Path, PathBuf};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
 
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value as JsonVal};
 
-use crate::db::Table;
+use crate::crypt::{file_id, Cipher, CHECK_LEN, ENCRYPTED, FILE_ID_LEN, WRONG_KEY};
+use crate::db::{now_millis, parse_date, Partition, Retention, Table};
 use crate::{Res, Row};
 
 fn open_file<P: AsRef<Path>>(path: P) -> io::Result<File> {
@@ -17,105 +24,1781 @@ fn open_file<P: AsRef<Path>>(path: P) -> io::Result<File> {
         .open(path)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// When writes to the logs are forced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Durability {
+    /// fsync before a write is acknowledged
+    Always,
+    /// fsync from a background task once a second
+    EverySecond,
+    /// leave flushing to the operating system
+    Os,
+}
+
+impl FromStr for Durability {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "always" => Ok(Durability::Always),
+            "every-second" => Ok(Durability::EverySecond),
+            "os" => Ok(Durability::Os),
+            _ => Err("durability must be one of always, every-second or os"),
+        }
+    }
+}
+
+/// Syncs the file if it was written to since the last sync.
+fn sync_file(file: &File, dirty: &AtomicBool) -> io::Result<()> {
+    if dirty.swap(false, Ordering::SeqCst) {
+        if let Err(err) = file.sync_data() {
+            dirty.store(true, Ordering::SeqCst);
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Writes buffered past this are flushed to the file without waiting for
+/// the next commit, so a large batch does not grow the buffer unbounded.
+const FLUSH_BYTES: usize = 1 << 20;
+
+/// How much of the end of a log is read first when looking for its last
+/// operation; the window doubles until it reaches back far enough.
+const TAIL_BYTES: usize = 64 << 10;
+
+/// Queues an encoded operation, flushing if the buffer has grown too big.
+fn buffer(file: &File, pending: &Mutex<Vec<u8>>, dirty: &AtomicBool, buf: &[u8]) -> io::Result<()> {
+    let mut pending = pending.lock().unwrap();
+    pending.extend_from_slice(buf);
+    if pending.len() >= FLUSH_BYTES {
+        write_pending(file, &mut pending, dirty)?;
+    }
+    Ok(())
+}
+
+/// Hands any buffered operations to the OS with a single write.
+fn flush_file(file: &File, pending: &Mutex<Vec<u8>>, dirty: &AtomicBool) -> io::Result<()> {
+    write_pending(file, &mut pending.lock().unwrap(), dirty)
+}
+
+fn write_pending(mut file: &File, pending: &mut Vec<u8>, dirty: &AtomicBool) -> io::Result<()> {
+    if !pending.is_empty() {
+        dirty.store(true, Ordering::SeqCst);
+        // reads move the cursor, but operations only ever go on the end
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(pending)?;
+        pending.clear();
+    }
+    Ok(())
+}
+
+/// Batches concurrent commits so that one flush and sync of the logs makes
+/// every write queued before it durable.
+///
+/// Each write is given a ticket, the count of writes applied so far. The
+/// first waiter to find no commit running becomes the leader and commits
+/// on behalf of everyone queued; the rest wait until a commit covers their
+/// ticket.
+#[derive(Debug, Default)]
+pub struct GroupCommit {
+    state: Mutex<CommitState>,
+    done: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct CommitState {
+    committed: u64,
+    running: bool,
+}
+
+impl GroupCommit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Blocks until the write with `ticket` is durable, running `commit` if
+    /// no other waiter is. `commit` returns the last ticket it made durable.
+    pub fn wait<F: FnOnce() -> io::Result<u64>>(&self, ticket: u64, commit: F) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while state.running && state.committed < ticket {
+            state = self.done.wait(state).unwrap();
+        }
+        if state.committed >= ticket {
+            return Ok(());
+        }
+        state.running = true;
+        drop(state);
+        let res = commit();
+        let mut state = self.state.lock().unwrap();
+        state.running = false;
+        if let Ok(upto) = res {
+            state.committed = state.committed.max(upto);
+        }
+        self.done.notify_all();
+        res.map(|_| ())
+    }
+}
+
+/// Whether a table or database name is safe to use as a file name in the
+/// data directory: 1 to 64 ASCII letters, digits, `_` or `-`.
+pub fn is_valid_name(name: &str) -> bool {
+    (1..=64).contains(&name.len()) && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// Names the partition of `table` holding the rows dated `date`.
+pub fn partition_name(table: &str, date: &str) -> String {
+    format!("{}.{}", table, date)
+}
+
+/// Splits a partition name into its table and date.
+pub fn split_partition(name: &str) -> Option<(&str, &str)> {
+    let (table, date) = name.rsplit_once('.')?;
+    if is_valid_name(table) && parse_date(date).is_some() {
+        Some((table, date))
+    } else {
+        None
+    }
+}
+
+/// Whether a name can be listed in the catalog: a valid name, or the name
+/// of a partition.
+pub fn is_valid_table_name(name: &str) -> bool {
+    is_valid_name(name) || split_partition(name).is_some()
+}
+
+/// Whether a path given by a client stays inside the directory it is
+/// resolved against: relative, with no `..` components.
+pub fn is_confined_path(path: &str) -> bool {
+    !path.is_empty() && Path::new(path).components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+fn check_name(name: &str) -> io::Result<()> {
+    check(name, is_valid_name(name))
+}
+
+fn check_table_name(name: &str) -> io::Result<()> {
+    check(name, is_valid_table_name(name))
+}
+
+fn check(name: &str, valid: bool) -> io::Result<()> {
+    if valid {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid name {:?}", name)))
+    }
+}
+
+/// How records are encoded in a table or key log.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// one framed JSON document per line
+    Json,
+    /// length and CRC prefixed bincode records after `BIN_MAGIC`
+    Bincode,
+}
+
+impl FromStr for LogFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "json" => Ok(LogFormat::Json),
+            "bincode" => Ok(LogFormat::Bincode),
+            _ => Err("log format must be one of json or bincode"),
+        }
+    }
+}
+
+/// The version of the on-disk format this build writes.
+///
+/// Files written before logs had a header are version 0 and are migrated
+/// when they are next read.
+pub const FORMAT_VERSION: u64 = 1;
+
+/// Starts every binary file, followed by a format version byte. JSON files
+/// start with a `["memson", version]` record instead.
+const BIN_MAGIC: &[u8] = b"\0memson";
+
+/// Starts every encrypted file, followed by a format version byte, a byte
+/// naming the format its records are encoded in, the file's id and a key
+/// check.
+const SEALED_MAGIC: &[u8] = b"\0memseal";
+
+/// Where the id of an encrypted file starts.
+const SEALED_ID: usize = SEALED_MAGIC.len() + 2;
+
+/// Bytes before the first record of an encrypted file.
+const SEALED_HEADER: usize = SEALED_ID + FILE_ID_LEN + CHECK_LEN;
+
+/// Seals the records written to one encrypted file.
+///
+/// Each record is bound to the file's id and the offset it is written at,
+/// so a record moved to another file, repeated, reordered or dropped from
+/// before the end fails to open.
+#[derive(Debug, Clone)]
+struct Sealer {
+    key: Cipher,
+    id: [u8; FILE_ID_LEN],
+    // the offset the next record is written at
+    end: u64,
+}
+
+impl Sealer {
+    /// Starts a new file, returning the sealer and the file's header.
+    fn create(key: &Cipher, format: LogFormat) -> io::Result<(Self, Vec<u8>)> {
+        let id = file_id();
+        let header = [SEALED_MAGIC, &[FORMAT_VERSION as u8, format as u8], &id, &key.check(&id)?].concat();
+        let sealer = Sealer { key: key.clone(), id, end: header.len() as u64 };
+        Ok((sealer, header))
+    }
+
+    /// Carries on sealing records at the end of `file`, if there is a key
+    /// and the file is encrypted.
+    fn resume(key: Option<&Cipher>, file: &mut File) -> io::Result<Option<Self>> {
+        let key = match key {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let mut header = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.take(SEALED_HEADER as u64).read_to_end(&mut header)?;
+        let end = file.seek(SeekFrom::End(0))?;
+        if header.len() < SEALED_HEADER || !header.starts_with(SEALED_MAGIC) {
+            return Ok(None);
+        }
+        let mut id = [0; FILE_ID_LEN];
+        id.copy_from_slice(&header[SEALED_ID..SEALED_ID + FILE_ID_LEN]);
+        Ok(Some(Sealer { key: key.clone(), id, end }))
+    }
+
+    /// Seals and frames the next record.
+    fn seal(&mut self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let buf = frame(&self.key.seal(payload, &record_aad(&self.id, self.end))?);
+        self.end += buf.len() as u64;
+        Ok(buf)
+    }
+}
+
+/// What a sealed record is bound to: its file and offset.
+fn record_aad(id: &[u8], at: u64) -> Vec<u8> {
+    [id, &at.to_le_bytes()].concat()
+}
+
+/// JSON as bincode can encode it.
+///
+/// bincode cannot deserialize `serde_json::Value` directly as it is not a
+/// self-describing format.
+#[derive(Serialize, Deserialize)]
+enum BinVal {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Str(String),
+    Arr(Vec<BinVal>),
+    Obj(Vec<(String, BinVal)>),
+}
+
+impl From<&JsonVal> for BinVal {
+    fn from(val: &JsonVal) -> Self {
+        match val {
+            JsonVal::Null => BinVal::Null,
+            JsonVal::Bool(b) => BinVal::Bool(*b),
+            JsonVal::Number(n) => {
+                if let Some(n) = n.as_u64() {
+                    BinVal::UInt(n)
+                } else if let Some(n) = n.as_i64() {
+                    BinVal::Int(n)
+                } else {
+                    BinVal::Float(n.as_f64().unwrap_or(0.0))
+                }
+            }
+            JsonVal::String(s) => BinVal::Str(s.clone()),
+            JsonVal::Array(arr) => BinVal::Arr(arr.iter().map(BinVal::from).collect()),
+            JsonVal::Object(obj) => BinVal::Obj(obj.iter().map(|(k, v)| (k.clone(), BinVal::from(v))).collect()),
+        }
+    }
+}
+
+impl From<BinVal> for JsonVal {
+    fn from(val: BinVal) -> Self {
+        match val {
+            BinVal::Null => JsonVal::Null,
+            BinVal::Bool(b) => JsonVal::Bool(b),
+            BinVal::Int(n) => JsonVal::from(n),
+            BinVal::UInt(n) => JsonVal::from(n),
+            BinVal::Float(n) => serde_json::Number::from_f64(n).map_or(JsonVal::Null, JsonVal::Number),
+            BinVal::Str(s) => JsonVal::String(s),
+            BinVal::Arr(arr) => JsonVal::Array(arr.into_iter().map(JsonVal::from).collect()),
+            BinVal::Obj(obj) => JsonVal::Object(obj.into_iter().map(|(k, v)| (k, JsonVal::from(v))).collect()),
+        }
+    }
+}
+
+/// Encodes a record in the given format, sealed by `seal` if the file is
+/// encrypted.
+///
+/// JSON records are framed as `<len>:<crc32>:<payload>\n`, and binary and
+/// sealed ones as a little-endian `u32` length and CRC followed by the
+/// payload, so a torn or corrupted record can be told apart from a good one
+/// on replay.
+fn encode<T: Serialize>(format: LogFormat, seal: Option<&mut Sealer>, val: &T) -> io::Result<Vec<u8>> {
+    let payload = match format {
+        LogFormat::Json => serde_json::to_vec(val)?,
+        LogFormat::Bincode => {
+            let val = BinVal::from(&serde_json::to_value(val)?);
+            bincode::serialize(&val).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+        }
+    };
+    match (format, seal) {
+        (_, Some(seal)) => seal.seal(&payload),
+        (LogFormat::Json, None) => {
+            let mut buf = format!("{}:{:08x}:", payload.len(), crc32fast::hash(&payload)).into_bytes();
+            buf.extend(payload);
+            buf.push(b'\n');
+            Ok(buf)
+        }
+        (LogFormat::Bincode, None) => Ok(frame(&payload)),
+    }
+}
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(payload.len() + 8);
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Encodes the header of a new file, with the sealer for its records if it
+/// is encrypted.
+fn encode_header(format: LogFormat, key: Option<&Cipher>) -> io::Result<(Vec<u8>, Option<Sealer>)> {
+    match (format, key) {
+        (_, Some(key)) => Sealer::create(key, format).map(|(seal, buf)| (buf, Some(seal))),
+        (LogFormat::Json, None) => Ok((encode(format, None, &("memson", FORMAT_VERSION))?, None)),
+        (LogFormat::Bincode, None) => Ok(([BIN_MAGIC, &[FORMAT_VERSION as u8]].concat(), None)),
+    }
+}
+
+/// Encodes a whole log: the header of the format followed by the records.
+fn encode_log(format: LogFormat, key: Option<&Cipher>, records: &[JsonVal]) -> io::Result<Vec<u8>> {
+    let (mut buf, mut seal) = encode_header(format, key)?;
+    for record in records {
+        buf.extend(encode(format, seal.as_mut(), record)?);
+    }
+    Ok(buf)
+}
+
+/// Decodes the payload of a binary or sealed record.
+fn decode_payload(format: LogFormat, payload: &[u8]) -> Option<JsonVal> {
+    match format {
+        LogFormat::Json => serde_json::from_slice(payload).ok(),
+        LogFormat::Bincode => bincode::deserialize::<BinVal>(payload).ok().map(JsonVal::from),
+    }
+}
+
+/// Decodes a JSON log line, framed or a bare document written before records
+/// were framed; `None` if the record is damaged.
+fn decode_line(line: &[u8]) -> Option<JsonVal> {
+    let line = std::str::from_utf8(line).ok()?;
+    if !line.starts_with(|c: char| c.is_ascii_digit()) {
+        return serde_json::from_str(line).ok();
+    }
+    let mut parts = line.splitn(3, ':');
+    let len: usize = parts.next()?.parse().ok()?;
+    let crc = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let payload = parts.next()?;
+    if payload.len() == len && crc32fast::hash(payload.as_bytes()) == crc {
+        serde_json::from_str(payload).ok()
+    } else {
+        None
+    }
+}
+
+/// Checks the framed record at the start of `buf`, returning its payload
+/// and length, or `Err(len)` with the length the damaged record claims.
+/// No record is empty, so a zeroed frame, whose CRC matches, is damaged.
+fn decode_frame(buf: &[u8]) -> Result<(&[u8], usize), usize> {
+    if buf.len() < 8 {
+        return Err(8);
+    }
+    let len = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    let crc = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+    let end = len.saturating_add(8);
+    match buf.get(8..end) {
+        Some(payload) if len > 0 && crc32fast::hash(payload) == crc => Ok((payload, end)),
+        _ => Err(end),
+    }
+}
+
+/// Where a log file is damaged, as a byte offset.
+#[derive(Debug, PartialEq)]
+enum Damage {
+    /// A record cut short by a crash, with nothing valid after it.
+    TornTail(u64),
+    /// A bad record followed by good ones.
+    Corrupt(u64),
+}
+
+/// The decoded contents of a file.
+struct Scan {
+    format: LogFormat,
+    version: u64,
+    records: Vec<JsonVal>,
+    damage: Option<Damage>,
+    // whether the file is encrypted
+    sealed: bool,
+}
+
+/// Decodes the records of a file, stopping at the first damaged one. An
+/// encrypted file is refused without `key`, or if it was sealed with another.
+fn scan(buf: &[u8], key: Option<&Cipher>) -> Res<Scan> {
+    if buf.starts_with(SEALED_MAGIC) {
+        let key = key.ok_or(ENCRYPTED)?;
+        if buf.len() < SEALED_HEADER {
+            return Err("encrypted file header is damaged");
+        }
+        let version = u64::from(buf[SEALED_MAGIC.len()]);
+        let format = match buf[SEALED_MAGIC.len() + 1] {
+            0 => LogFormat::Json,
+            1 => LogFormat::Bincode,
+            _ => return Err("encrypted file has an unknown record format"),
+        };
+        let id = &buf[SEALED_ID..SEALED_ID + FILE_ID_LEN];
+        if !key.verify(&buf[SEALED_ID + FILE_ID_LEN..SEALED_HEADER], id) {
+            return Err(WRONG_KEY);
+        }
+        let (records, damage) = scan_frames(buf, SEALED_HEADER, |at, payload| {
+            decode_payload(format, &key.open(payload, &record_aad(id, at as u64))?)
+        });
+        Ok(Scan { format, version, records, damage, sealed: true })
+    } else if buf.len() > BIN_MAGIC.len() && buf.starts_with(BIN_MAGIC) {
+        let (records, damage) = scan_frames(buf, BIN_MAGIC.len() + 1, |_, payload| decode_payload(LogFormat::Bincode, payload));
+        let version = u64::from(buf[BIN_MAGIC.len()]);
+        Ok(Scan { format: LogFormat::Bincode, version, records, damage, sealed: false })
+    } else {
+        let (mut records, damage) = scan_lines(buf);
+        let version = match records.first().and_then(|record| parse_marker(record, "memson")) {
+            Some(version) => {
+                records.remove(0);
+                version
+            }
+            None => 0,
+        };
+        Ok(Scan { format: LogFormat::Json, version, records, damage, sealed: false })
+    }
+}
+
+/// Scans a file read from `path`, refusing one written by a newer build.
+fn scan_file(path: &Path, buf: &[u8], key: Option<&Cipher>) -> Res<Scan> {
+    let scan = scan(buf, key).inspect_err(|err| eprintln!("cannot read {:?}: {}", path, err))?;
+    check_version(path, scan.version)?;
+    Ok(scan)
+}
+
+/// Refuses a file written by a newer build in a format this one cannot read.
+fn check_version(path: &Path, version: u64) -> Res<()> {
+    if version > FORMAT_VERSION {
+        eprintln!(
+            "{:?} has format version {} but this build reads up to version {}; upgrade memson to open it",
+            path, version, FORMAT_VERSION
+        );
+        return Err("unsupported format version");
+    }
+    Ok(())
+}
+
+fn scan_lines(buf: &[u8]) -> (Vec<JsonVal>, Option<Damage>) {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let end = buf[pos..].iter().position(|b| *b == b'\n').map(|i| pos + i);
+        match end.and_then(|end| decode_line(&buf[pos..end])) {
+            Some(record) => {
+                records.push(record);
+                pos = end.unwrap() + 1;
+            }
+            None => {
+                let damage = if valid_line_after(&buf[pos..]) {
+                    Damage::Corrupt(pos as u64)
+                } else {
+                    Damage::TornTail(pos as u64)
+                };
+                return (records, Some(damage));
+            }
+        }
+    }
+    (records, None)
+}
+
+/// Whether any complete record follows the first line of `buf`.
+fn valid_line_after(buf: &[u8]) -> bool {
+    let mut lines = buf.split(|b| *b == b'\n');
+    lines.next();
+    let mut lines: Vec<&[u8]> = lines.collect();
+    // the last piece was not followed by a newline, so it is incomplete
+    lines.pop();
+    lines.iter().any(|line| decode_line(line).is_some())
+}
+
+/// Decodes the framed records of a binary or encrypted file from `pos`,
+/// passing `decode` the offset of each record along with its payload.
+fn scan_frames<F: Fn(usize, &[u8]) -> Option<JsonVal>>(buf: &[u8], mut pos: usize, decode: F) -> (Vec<JsonVal>, Option<Damage>) {
+    let mut records = Vec::new();
+    while pos < buf.len() {
+        // a crash can leave the space a file system preallocated as zeros
+        if buf[pos..].iter().all(|b| *b == 0) {
+            return (records, Some(Damage::TornTail(pos as u64)));
+        }
+        match decode_frame(&buf[pos..]) {
+            Ok((payload, len)) => match decode(pos, payload) {
+                Some(record) => {
+                    records.push(record);
+                    pos += len;
+                }
+                // the CRC matches, so this is no torn write: the record was
+                // altered, or fails authentication if the file is encrypted
+                None => return (records, Some(Damage::Corrupt(pos as u64))),
+            },
+            Err(len) => {
+                // a whole record that fails its check, or a length running
+                // past the end with good records after it, is not what a
+                // crash mid-write leaves behind
+                let damage = if len < buf.len() - pos || valid_frame_after(buf, pos, &decode) {
+                    Damage::Corrupt(pos as u64)
+                } else {
+                    Damage::TornTail(pos as u64)
+                };
+                return (records, Some(damage));
+            }
+        }
+    }
+    (records, None)
+}
+
+/// Whether any complete record starts after `pos`, the binary counterpart
+/// of `valid_line_after`.
+fn valid_frame_after<F: Fn(usize, &[u8]) -> Option<JsonVal>>(buf: &[u8], pos: usize, decode: &F) -> bool {
+    (pos + 1..buf.len()).any(|at| matches!(decode_frame(&buf[at..]), Ok((payload, _)) if decode(at, payload).is_some()))
+}
+
+/// How far reading a log backwards from its end got.
+enum Tail {
+    /// The stamp of the last operation, or of the checkpoint if none was
+    /// logged since.
+    Done(Option<Stamp>),
+    /// The part read does not reach back to the last operation.
+    Short,
+    /// The tail is damaged, so the log has to be scanned from the start.
+    Damaged,
+}
+
+/// Looks for the last stamp in `buf`, the end of a JSON log. `whole` is
+/// whether `buf` starts at the beginning of the file.
+fn tail_lines(buf: &[u8], whole: bool) -> Tail {
+    // anything after the last newline is a torn write, which a scan drops
+    let end = buf.iter().rposition(|b| *b == b'\n').map_or(0, |end| end + 1);
+    let mut lines: Vec<&[u8]> = buf[..end].split(|b| *b == b'\n').collect();
+    lines.pop();
+    // the first line may have been cut by the start of the window
+    let lines = if whole { &lines[..] } else { &lines[lines.len().min(1)..] };
+    let mut records = Vec::new();
+    for line in lines.iter().rev() {
+        match decode_line(line) {
+            Some(record) => match Stamp::parse(&record, "at") {
+                Some(stamp) => return Tail::Done(Some(stamp)),
+                None => records.push(record),
+            },
+            None => return Tail::Damaged,
+        }
+    }
+    if !whole {
+        return Tail::Short;
+    }
+    records.retain(|record| parse_marker(record, "memson").is_none());
+    Tail::Done(records.last().and_then(|record| Stamp::parse(record, "checkpoint")))
+}
+
+/// Looks for the last stamp in `buf`, the end of a binary or encrypted log
+/// from byte `start`, walking back one record at a time. `first` is where
+/// the records of the file begin.
+fn tail_frames<F: Fn(usize, &[u8]) -> Option<JsonVal>>(buf: &[u8], start: usize, first: usize, decode: F) -> Tail {
+    let mut end = start + buf.len();
+    let mut records = Vec::new();
+    while end > start {
+        // the last record is the one whose length reaches exactly to `end`
+        let at = (start..end.saturating_sub(7)).rev().find(|at| {
+            let len = &buf[at - start..at - start + 4];
+            u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize + 8 == end - at
+                && decode_frame(&buf[at - start..end - start]).is_ok()
+        });
+        let at = match at {
+            Some(at) => at,
+            None if start > first => return Tail::Short,
+            None => return Tail::Damaged,
+        };
+        let (payload, _) = decode_frame(&buf[at - start..end - start]).unwrap();
+        match decode(at, payload) {
+            Some(record) => match Stamp::parse(&record, "at") {
+                Some(stamp) => return Tail::Done(Some(stamp)),
+                None => records.push(record),
+            },
+            None => return Tail::Damaged,
+        }
+        end = at;
+    }
+    if start > first {
+        return Tail::Short;
+    }
+    Tail::Done(records.last().and_then(|record| Stamp::parse(record, "checkpoint")))
+}
+
+/// Reads every record of a log, truncating a torn tail left by a crash.
+///
+/// Damage followed by good records is not something a crash leaves behind,
+/// so it is reported and refused rather than silently dropped. A log in an
+/// older format version is rewritten in the current one, and a plain log is
+/// encrypted once there is a key.
+fn read_log(file: &mut File, path: &Path, key: Option<&Cipher>) -> Res<(LogFormat, Vec<JsonVal>)> {
+    let mut buf = Vec::new();
+    file.seek(SeekFrom::Start(0))
+        .and_then(|_| file.read_to_end(&mut buf))
+        .map_err(|_| "cannot read log")?;
+    let Scan { format, version, records, damage, sealed } = scan_file(path, &buf, key)?;
+    match damage {
+        None => {}
+        Some(Damage::TornTail(at)) => {
+            eprintln!("truncating torn record at byte {} of {:?}", at, path);
+            file.set_len(at).map_err(|_| "cannot truncate log")?;
+            file.seek(SeekFrom::End(0)).map_err(|_| "cannot truncate log")?;
+        }
+        Some(Damage::Corrupt(at)) => {
+            eprintln!(
+                "corrupt or tampered record at byte {} of {:?}; run with --repair to drop it and every record after it",
+                at, path
+            );
+            return Err("corrupt log record");
+        }
+    }
+    if version < FORMAT_VERSION || (key.is_some() && !sealed) {
+        if version < FORMAT_VERSION {
+            eprintln!("migrating {:?} from format version {} to {}", path, version, FORMAT_VERSION);
+        } else {
+            eprintln!("encrypting {:?}", path);
+        }
+        *file = encode_log(format, key, &records)
+            .and_then(|buf| replace_file(path, &buf))
+            .and_then(|_| open_file(path))
+            .and_then(|mut file| file.seek(SeekFrom::End(0)).map(|_| file))
+            .map_err(|_| "cannot migrate log")?;
+    }
+    Ok((format, records))
+}
+
+/// Opens a log, writing the header of `format` if the log is new, and
+/// returns the format the log is in.
+fn open_log(path: &Path, format: LogFormat, key: Option<&Cipher>) -> io::Result<(File, LogFormat)> {
+    let mut file = open_file(path)?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(&encode_log(format, key, &[])?)?;
+        return Ok((file, format));
+    }
+    let mut head = Vec::new();
+    (&mut file).take(SEALED_MAGIC.len() as u64 + 2).read_to_end(&mut head)?;
+    file.seek(SeekFrom::End(0))?;
+    let format = if head.starts_with(SEALED_MAGIC) {
+        // the format byte follows the magic and the version
+        if head.get(SEALED_MAGIC.len() + 1) == Some(&1) {
+            LogFormat::Bincode
+        } else {
+            LogFormat::Json
+        }
+    } else if head.starts_with(BIN_MAGIC) {
+        LogFormat::Bincode
+    } else {
+        LogFormat::Json
+    };
+    Ok((file, format))
+}
+
+/// Rewrites a log in `format`, returning the reopened file.
+fn convert_log(file: &mut File, path: &Path, format: LogFormat, key: Option<&Cipher>) -> io::Result<File> {
+    let (_, records) = read_log(file, path, key).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    replace_file(path, &encode_log(format, key, &records)?)?;
+    let mut file = open_file(path)?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(file)
+}
+
+/// Truncates the file at its first damaged record, returning how many bytes
+/// were dropped.
+pub fn repair_file<P: AsRef<Path>>(path: P, key: Option<&Cipher>) -> io::Result<u64> {
+    let buf = match fs::read(&path) {
+        Ok(buf) => buf,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let scan = scan_file(path.as_ref(), &buf, key).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    match scan.damage {
+        Some(Damage::TornTail(at)) | Some(Damage::Corrupt(at)) => {
+            let file = OpenOptions::new().write(true).open(&path)?;
+            file.set_len(at)?;
+            file.sync_all()?;
+            Ok(buf.len() as u64 - at)
+        }
+        None => Ok(0),
+    }
+}
+
+/// Repairs the catalog, key log and table logs of a database, returning each
+/// file checked and how many bytes were dropped from it.
+pub fn repair<P: AsRef<Path>>(root: P, name: &str, key: Option<&Cipher>) -> Res<Vec<(PathBuf, u64)>> {
+    let root = root.as_ref();
+    let mut report = Vec::new();
+    let mut check = |path: PathBuf| -> Res<()> {
+        let dropped = repair_file(&path, key).map_err(|_| "cannot repair file")?;
+        report.push((path, dropped));
+        Ok(())
+    };
+    let db_path = root.join(name.to_string() + ".db");
+    check(db_path.clone())?;
+    check(root.join(name.to_string() + ".keys"))?;
+    let buf = match fs::read(&db_path) {
+        Ok(buf) => buf,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(report),
+        Err(_) => return Err("cannot read db config"),
+    };
+    let mut catalog = Catalog::default();
+    for record in scan_file(&db_path, &buf, key)?.records {
+        catalog.apply(record)?;
+    }
+    // tables are always stored under the root, whatever path was recorded
+    for config in catalog.tables {
+        check_table_name(&config.table).map_err(|_| "invalid table name in db config")?;
+        let path = root.join(config.table + ".table");
+        check(path.clone())?;
+        read_snapshot(path.with_extension("snapshot"), key)?;
+    }
+    Ok(report)
+}
+
+/// The point a database is recovered to: the last operation at or before a
+/// sequence number, or a time in milliseconds since the unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecoveryPoint {
+    Seq(u64),
+    Time(u64),
+}
+
+impl RecoveryPoint {
+    fn includes(&self, stamp: &Stamp) -> bool {
+        match *self {
+            RecoveryPoint::Seq(seq) => stamp.seq <= seq,
+            RecoveryPoint::Time(at) => stamp.at <= at,
+        }
+    }
+}
+
+impl FromStr for RecoveryPoint {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = "recovery point must be seq:N or time:MILLIS";
+        let (kind, n) = match s.find(':') {
+            Some(i) => (&s[..i], s[i + 1..].parse().map_err(|_| bad)?),
+            None => return Err(bad),
+        };
+        match kind {
+            "seq" => Ok(RecoveryPoint::Seq(n)),
+            "time" => Ok(RecoveryPoint::Time(n)),
+            _ => Err(bad),
+        }
+    }
+}
+
+/// Refuses a log whose snapshot holds operations after the recovery point, as
+/// they can no longer be told apart from the ones before it.
+fn check_checkpoint(path: &Path, records: &[JsonVal], point: RecoveryPoint) -> Res<()> {
+    match records.first().and_then(|r| Stamp::parse(r, "checkpoint")) {
+        Some(stamp) if !point.includes(&stamp) => {
+            eprintln!("{:?} was snapshotted at seq {} after the recovery point", path, stamp.seq);
+            Err("table was snapshotted after the recovery point")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Drops every operation logged after the recovery point, returning how many
+/// records were dropped. Records logged before operations were stamped are
+/// kept. The original file is kept next to it as `.before-recovery`.
+pub fn recover_file<P: AsRef<Path>>(path: P, point: RecoveryPoint, key: Option<&Cipher>) -> Res<usize> {
+    let path = path.as_ref();
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(_) => return Err("cannot open log"),
+    };
+    let (format, records) = read_log(&mut file, path, key)?;
+    check_checkpoint(path, &records, point)?;
+    let keep = records
+        .iter()
+        .position(|r| Stamp::parse(r, "at").is_some_and(|stamp| !point.includes(&stamp)))
+        .unwrap_or(records.len());
+    let dropped = records.len() - keep;
+    if dropped > 0 {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(".before-recovery");
+        fs::copy(path, backup).map_err(|_| "cannot back up log")?;
+        encode_log(format, key, &records[..keep])
+            .and_then(|buf| replace_file(path, &buf))
+            .map_err(|_| "cannot rewrite log")?;
+    }
+    Ok(dropped)
+}
+
+/// Drops the records of the operation stamped `seq` from a log, keeping
+/// those before and after it. Returns how many records were dropped.
+fn drop_operation(path: &Path, seq: u64, key: Option<&Cipher>) -> Res<usize> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(_) => return Err("cannot open log"),
+    };
+    let (format, mut records) = read_log(&mut file, path, key)?;
+    let start = match records.iter().position(|r| Stamp::parse(r, "at").is_some_and(|stamp| stamp.seq == seq)) {
+        Some(start) => start,
+        None => return Ok(0),
+    };
+    let end = records[start + 1..]
+        .iter()
+        .position(|r| Stamp::parse(r, "at").is_some())
+        .map_or(records.len(), |i| start + 1 + i);
+    records.drain(start..end);
+    encode_log(format, key, &records)
+        .and_then(|buf| replace_file(path, &buf))
+        .map_err(|_| "cannot rewrite log")?;
+    Ok(end - start)
+}
+
+/// Rolls the key log and every table log of a database back to the recovery
+/// point, returning each file and how many records were dropped from it. Every
+/// file is checked before any is changed.
+pub fn recover<P: AsRef<Path>>(root: P, name: &str, point: RecoveryPoint, key: Option<&Cipher>) -> Res<Vec<(PathBuf, usize)>> {
+    let root = root.as_ref();
+    let mut paths = vec![root.join(name.to_string() + ".keys")];
+    let db_path = root.join(name.to_string() + ".db");
+    let buf = match fs::read(&db_path) {
+        Ok(buf) => buf,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(_) => return Err("cannot read db config"),
+    };
+    let mut catalog = Catalog::default();
+    for record in scan_file(&db_path, &buf, key)?.records {
+        catalog.apply(record)?;
+    }
+    paths.extend(catalog.tables.iter().map(|config| root.join(config.table.clone() + ".table")));
+    for path in &paths {
+        let buf = match fs::read(path) {
+            Ok(buf) => buf,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(_) => return Err("cannot read log"),
+        };
+        let scan = scan_file(path, &buf, key)?;
+        check_checkpoint(path, &scan.records, point)?;
+    }
+    let mut report = Vec::new();
+    for path in paths {
+        let dropped = recover_file(&path, point, key)?;
+        report.push((path, dropped));
+    }
+    Ok(report)
+}
+
+/// A database's state, taken under its lock and written out as a backup once
+/// the lock is released. The keys are copied, while the tables are read from
+/// their files, opened under the lock.
+pub struct Backup {
+    pub name: String,
+    pub format: LogFormat,
+    // the last operation the backup holds
+    pub seq: u64,
+    pub tables: Vec<PinnedTable>,
+    pub partitions: BTreeMap<String, Partition>,
+    pub keys: Vec<KeyOp>,
+    // the key the backup is encrypted with, that of the database
+    pub key: Option<Cipher>,
+}
+
+impl Backup {
+    /// Writes the backup into `dir`, returning the sequence number it was
+    /// taken at.
+    ///
+    /// Every table is written as a snapshot with an empty log, and the
+    /// catalog goes last so an interrupted backup cannot be restored.
+    pub fn write<P: AsRef<Path>>(&self, dir: P) -> Res<u64> {
+        let dir = dir.as_ref();
+        if dir.join(self.name.clone() + ".db").exists() {
+            return Err("backup directory already holds a backup");
+        }
+        self.write_files(dir).map_err(|err| {
+            eprintln!("cannot write backup to {:?}: {}", dir, err);
+            "cannot write backup"
+        })?;
+        Ok(self.seq)
+    }
+
+    fn write_files(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let stamp = Stamp::now(self.seq);
+        let marker = serde_json::json!(["checkpoint", 1, stamp.seq, stamp.at]);
+        let key = self.key.as_ref();
+        let mut configs = Vec::with_capacity(self.tables.len());
+        for table in &self.tables {
+            let rows = table.rows().map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let path = dir.join(table.name.clone() + ".table");
+            write_snapshot(path.with_extension("snapshot"), self.format, key, 1, &rows)?;
+            replace_file(&path, &encode_log(self.format, key, std::slice::from_ref(&marker))?)?;
+            let partition = self.partitions.get(&table.name).cloned();
+            configs.push(TableConfig { table: table.name.clone(), path, retention: table.retention.clone(), partition });
+        }
+        let mut keys = vec![stamp.record()];
+        for op in &self.keys {
+            keys.push(serde_json::to_value(op)?);
+        }
+        replace_file(dir.join(self.name.clone() + ".keys"), &encode_log(self.format, key, &keys)?)?;
+        replace_file(dir.join(self.name.clone() + ".db"), &encode_catalog(self.format, key, &configs)?)
+    }
+}
+
+/// Copies a backup written by `Backup::write` into the data directory,
+/// returning the files restored. An existing database is never overwritten.
+/// The files are copied as they are, so an encrypted backup needs its key.
+pub fn restore<P: AsRef<Path>, Q: AsRef<Path>>(from: P, root: Q, name: &str, key: Option<&Cipher>) -> Res<Vec<PathBuf>> {
+    let (from, root) = (from.as_ref(), root.as_ref());
+    let db_path = root.join(name.to_string() + ".db");
+    if db_path.exists() {
+        return Err("database already exists; move its files away to restore over it");
+    }
+    let backup_path = from.join(name.to_string() + ".db");
+    let buf = fs::read(&backup_path).map_err(|_| "cannot read backup catalog")?;
+    let scan = scan_file(&backup_path, &buf, key)?;
+    if scan.damage.is_some() {
+        return Err("backup catalog is damaged");
+    }
+    let mut catalog = Catalog::default();
+    for record in scan.records {
+        catalog.apply(record)?;
+    }
+    let mut files = vec![name.to_string() + ".keys"];
+    for config in &catalog.tables {
+        check_table_name(&config.table).map_err(|_| "backup lists a bad table name")?;
+        files.push(config.table.clone() + ".table");
+        files.push(config.table.clone() + ".snapshot");
+    }
+    let mut restored = Vec::with_capacity(files.len() + 1);
+    for file in files {
+        let path = root.join(&file);
+        fs::read(from.join(&file))
+            .and_then(|buf| replace_file(&path, &buf))
+            .map_err(|err| {
+                eprintln!("cannot restore {:?}: {}", file, err);
+                "cannot restore backup file"
+            })?;
+        restored.push(path);
+    }
+    // the catalog goes last, pointing at the restored files
+    for config in catalog.tables.iter_mut() {
+        config.path = root.join(config.table.clone() + ".table");
+    }
+    let format = catalog.format.unwrap_or(LogFormat::Json);
+    encode_catalog(format, key, &catalog.tables)
+        .and_then(|buf| replace_file(&db_path, &buf))
+        .map_err(|_| "cannot write db config")?;
+    restored.push(db_path);
+    Ok(restored)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct TableConfig {
     table: String,
     path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retention: Option<Retention>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    partition: Option<Partition>,
+}
+
+/// A change to the table files that a crash may have interrupted, naming
+/// the tables rather than their recorded paths, as tables always live under
+/// the root.
+#[derive(Debug)]
+enum FileOp {
+    Remove(String),
+    Move(String, String),
+}
+
+/// The catalog as replayed from its records.
+///
+/// Tables are listed as `TableConfig` records, the last listing of a table
+/// winning. Drops and renames are journaled as `["drop", name]` and
+/// `["rename", from, to]` records before their files are touched, then
+/// folded in when the catalog is compacted.
+#[derive(Debug, Default)]
+struct Catalog {
+    tables: Vec<TableConfig>,
+    format: Option<LogFormat>,
+    // file changes journaled since the catalog was last compacted
+    pending: Vec<FileOp>,
+    // whether compacting would drop any records
+    stale: bool,
+}
+
+impl Catalog {
+    fn apply(&mut self, record: JsonVal) -> Res<()> {
+        if let Some(format) = parse_format(&record) {
+            self.stale |= self.format.is_some();
+            self.format = Some(format);
+            return Ok(());
+        }
+        let op = record.as_array().map(Vec::as_slice);
+        if let Some([JsonVal::String(tag), JsonVal::String(name)]) = op {
+            if tag == "drop" {
+                let index = self.find(name).ok_or("cannot find dropped table")?;
+                self.tables.remove(index);
+                self.pending.push(FileOp::Remove(name.to_string()));
+                self.stale = true;
+                return Ok(());
+            }
+        }
+        if let Some([JsonVal::String(tag), JsonVal::String(from), JsonVal::String(to)]) = op {
+            if tag == "rename" {
+                let index = self.find(from).ok_or("cannot find renamed table")?;
+                let config = &mut self.tables[index];
+                let path = config.path.with_file_name(to.to_string() + ".table");
+                self.pending.push(FileOp::Move(from.to_string(), to.to_string()));
+                config.table = to.to_string();
+                config.path = path;
+                self.stale = true;
+                return Ok(());
+            }
+        }
+        let config: TableConfig = serde_json::from_value(record).map_err(|_| "cannot deserialize table config")?;
+        match self.find(&config.table) {
+            Some(index) => {
+                self.tables[index] = config;
+                self.stale = true;
+            }
+            None => self.tables.push(config),
+        }
+        Ok(())
+    }
+
+    fn find(&self, table: &str) -> Option<usize> {
+        self.tables.iter().position(|c| c.table == table)
+    }
+}
+
+/// The files a table is stored in: its log and its snapshot.
+fn table_files(path: &Path) -> [PathBuf; 2] {
+    [path.to_path_buf(), path.with_extension("snapshot")]
+}
+
+/// Encodes a compacted catalog: the log format followed by every table.
+fn encode_catalog(format: LogFormat, key: Option<&Cipher>, tables: &[TableConfig]) -> io::Result<Vec<u8>> {
+    let mut records = vec![serde_json::to_value(("format", format))?];
+    for config in tables {
+        records.push(serde_json::to_value(config)?);
+    }
+    encode_log(LogFormat::Json, key, &records)
+}
+
+/// Syncs the directory holding `path`, so renames and removals in it are
+/// durable.
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+fn remove_table_files(path: &Path) -> io::Result<()> {
+    for file in table_files(path).iter() {
+        if let Err(err) = fs::remove_file(file) {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(err);
+            }
+        }
+    }
+    sync_dir(path)
+}
+
+/// Moves a table's files, skipping any already moved.
+fn move_table_files(from: &Path, to: &Path) -> io::Result<()> {
+    for (from, to) in table_files(from).iter().zip(table_files(to).iter()) {
+        if from.exists() && !to.exists() {
+            fs::rename(from, to)?;
+        }
+    }
+    sync_dir(to)
+}
+
+/// A table listed in the catalog, not yet loaded.
+#[derive(Debug, Clone)]
+pub struct TableSource {
+    pub name: String,
+    path: PathBuf,
+    retention: Option<Retention>,
+    key: Option<Cipher>,
+}
+
+impl TableSource {
+    pub fn new(name: String, path: PathBuf, retention: Option<Retention>, key: Option<Cipher>) -> Self {
+        TableSource { name, path, retention, key }
+    }
+
+    /// The last operation in the table's log, read without loading it. The
+    /// log is read backwards from its end, so only the last operation is
+    /// decoded, unless its tail is damaged.
+    pub fn last_stamp(&self) -> Option<Stamp> {
+        let mut file = File::open(&self.path).ok()?;
+        let len = file.metadata().ok()?.len() as usize;
+        let mut head = Vec::new();
+        (&mut file).take(SEALED_HEADER as u64).read_to_end(&mut head).ok()?;
+        let mut window = TAIL_BYTES;
+        loop {
+            let tail = |first: usize, file: &mut File| -> Option<(Vec<u8>, usize)> {
+                let start = len.saturating_sub(window).max(first);
+                let mut buf = Vec::new();
+                file.seek(SeekFrom::Start(start as u64)).ok()?;
+                file.read_to_end(&mut buf).ok()?;
+                Some((buf, start))
+            };
+            let tail = if head.starts_with(SEALED_MAGIC) {
+                let key = self.key.as_ref()?;
+                let id = head.get(SEALED_ID..SEALED_ID + FILE_ID_LEN)?;
+                let format = match head[SEALED_MAGIC.len() + 1] {
+                    0 => LogFormat::Json,
+                    1 => LogFormat::Bincode,
+                    _ => return None,
+                };
+                if !key.verify(head.get(SEALED_ID + FILE_ID_LEN..SEALED_HEADER)?, id) {
+                    return None;
+                }
+                let (buf, start) = tail(SEALED_HEADER, &mut file)?;
+                tail_frames(&buf, start, SEALED_HEADER, |at, payload| {
+                    decode_payload(format, &key.open(payload, &record_aad(id, at as u64))?)
+                })
+            } else if head.len() > BIN_MAGIC.len() && head.starts_with(BIN_MAGIC) {
+                let first = BIN_MAGIC.len() + 1;
+                let (buf, start) = tail(first, &mut file)?;
+                tail_frames(&buf, start, first, |_, payload| decode_payload(LogFormat::Bincode, payload))
+            } else {
+                let (buf, start) = tail(0, &mut file)?;
+                tail_lines(&buf, start == 0)
+            };
+            match tail {
+                Tail::Done(stamp) => return stamp,
+                Tail::Short => window *= 2,
+                Tail::Damaged => break,
+            }
+        }
+        let buf = fs::read(&self.path).ok()?;
+        let records = scan(&buf, self.key.as_ref()).ok()?.records;
+        records
+            .iter()
+            .rev()
+            .find_map(|r| Stamp::parse(r, "at"))
+            .or_else(|| Stamp::parse(records.first()?, "checkpoint"))
+    }
+
+    /// Opens the table's files to be read once the database lock is released.
+    pub fn pin(&self) -> io::Result<PinnedTable> {
+        PinnedTable::open(self.name.clone(), self.path.clone(), self.retention.clone(), self.key.clone())
+    }
+
+    pub fn load(self) -> Res<Table> {
+        let mut table = Table::open(self.name, self.path, self.key.as_ref())?;
+        table.set_retention(self.retention);
+        Ok(table)
+    }
+}
+
+/// A table's files, opened under the database lock and read after it is
+/// released. Logs are only ever appended to or replaced whole, so the open
+/// files keep the table as it was: the snapshot, and the log up to `len`.
+#[derive(Debug)]
+pub struct PinnedTable {
+    pub name: String,
+    pub retention: Option<Retention>,
+    path: PathBuf,
+    log: File,
+    len: u64,
+    snapshot: Option<File>,
+    key: Option<Cipher>,
+}
+
+impl PinnedTable {
+    /// Opens the files of a table whose log is flushed.
+    pub fn open(name: String, path: PathBuf, retention: Option<Retention>, key: Option<Cipher>) -> io::Result<Self> {
+        let log = File::open(&path)?;
+        let len = log.metadata()?.len();
+        let snapshot = match File::open(path.with_extension("snapshot")) {
+            Ok(file) => Some(file),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        Ok(PinnedTable { name, retention, path, log, len, snapshot, key })
+    }
+
+    /// Reads the rows the table held when it was pinned. The files are only
+    /// read, so a torn tail is skipped rather than truncated.
+    pub fn rows(&self) -> Res<Vec<Row>> {
+        let key = self.key.as_ref();
+        let mut buf = Vec::new();
+        (&self.log).take(self.len).read_to_end(&mut buf).map_err(|_| "cannot read log")?;
+        let scan = scan_file(&self.path, &buf, key)?;
+        if let Some(Damage::Corrupt(at)) = scan.damage {
+            eprintln!("corrupt or tampered record at byte {} of {:?}", at, self.path);
+            return Err("corrupt log record");
+        }
+        let (checkpoint, _, tail) = log_rows(scan.records)?;
+        let (snapshot_id, mut rows) = match self.snapshot {
+            Some(ref file) => {
+                let path = self.path.with_extension("snapshot");
+                let mut buf = Vec::new();
+                (&*file).read_to_end(&mut buf).map_err(|_| "cannot open snapshot")?;
+                let scan = scan_file(&path, &buf, key)?;
+                snapshot_rows(&path, scan.records, scan.damage)?
+            }
+            None => (0, Vec::new()),
+        };
+        // as in `Table::open`, a log lagging its snapshot is covered by it
+        match checkpoint.unwrap_or(0) {
+            checkpoint if checkpoint == snapshot_id => rows.extend(tail),
+            checkpoint if checkpoint < snapshot_id => {}
+            _ => return Err("table snapshot is missing"),
+        }
+        Ok(rows)
+    }
+}
+
+/// Loads tables on `threads` threads, or one per CPU if 0, passing each to
+/// `loaded` as soon as it is ready and reporting progress as it goes. Stops
+/// at the first table that cannot be loaded.
+pub fn load_tables<F: Fn(Table) + Sync>(sources: Vec<TableSource>, threads: usize, loaded: F) -> Res<()> {
+    let total = sources.len();
+    if total == 0 {
+        return Ok(());
+    }
+    let threads = match threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let start = Instant::now();
+    let queue = Mutex::new(sources.into_iter());
+    let done = AtomicUsize::new(0);
+    let failed = Mutex::new(None);
+    thread::scope(|scope| {
+        for _ in 0..threads.min(total) {
+            scope.spawn(|| loop {
+                if failed.lock().unwrap().is_some() {
+                    return;
+                }
+                let source = match queue.lock().unwrap().next() {
+                    Some(source) => source,
+                    None => return,
+                };
+                let (name, began) = (source.name.clone(), Instant::now());
+                match source.load() {
+                    Ok(table) => {
+                        let n = done.fetch_add(1, Ordering::SeqCst) + 1;
+                        eprintln!("loaded table {} ({}/{}): {} rows in {:?}", name, n, total, table.len(), began.elapsed());
+                        loaded(table);
+                    }
+                    Err(err) => {
+                        eprintln!("cannot load table {}: {}", name, err);
+                        *failed.lock().unwrap() = Some(err);
+                    }
+                }
+            });
+        }
+    });
+    if let Some(err) = failed.into_inner().unwrap() {
+        return Err(err);
+    }
+    eprintln!("loaded {} tables in {:?}", total, start.elapsed());
+    Ok(())
+}
+
+/// The catalog of a database's tables.
+#[derive(Debug)]
+pub struct DbConfig {
+    name: String,
+    root_path: PathBuf,
+    path: PathBuf,
+    file: File,
+    catalog: Catalog,
+    // the key every file of the database is encrypted with
+    key: Option<Cipher>,
+    seal: Option<Sealer>,
+}
+
+impl DbConfig {
+    pub fn open<P: AsRef<Path>, S: Into<String>>(root: P, name: S, key: Option<&Cipher>) -> io::Result<Self> {
+        let mut root_path = PathBuf::new();
+        root_path.push(root);
+        let mut path = root_path.clone();
+        let name = name.into();
+        check_name(&name)?;
+        let test_db = name.clone() + ".db";
+        path.push(test_db);
+        let (mut file, _) = open_log(&path, LogFormat::Json, key)?;
+        let seal = Sealer::resume(key, &mut file)?;
+        Ok(Self {
+            name,
+            root_path,
+            path,
+            file,
+            catalog: Catalog::default(),
+            key: key.cloned(),
+            seal,
+        })
+    }
+
+    /// The key the database's files are encrypted with, if any.
+    pub fn key(&self) -> Option<&Cipher> {
+        self.key.as_ref()
+    }
+
+    fn table_path(&self, table: &str) -> PathBuf {
+        self.root_path.join(table.to_string() + ".table")
+    }
+
+    /// Appends a record and syncs it, so a catalog change is on disk before
+    /// the table files it describes are touched.
+    fn append(&mut self, record: JsonVal) -> io::Result<()> {
+        self.file.write_all(&encode(LogFormat::Json, self.seal.as_mut(), &record)?)?;
+        self.file.sync_data()?;
+        self.catalog
+            .apply(record)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Rewrites the catalog with only the live tables, via a temporary file
+    /// renamed into place.
+    fn compact(&mut self) -> io::Result<()> {
+        let format = self.catalog.format.unwrap_or(LogFormat::Json);
+        replace_file(&self.path, &encode_catalog(format, self.key.as_ref(), &self.catalog.tables)?)?;
+        self.file = open_file(&self.path)?;
+        self.seal = Sealer::resume(self.key.as_ref(), &mut self.file)?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.catalog.pending.clear();
+        self.catalog.stale = false;
+        Ok(())
+    }
+
+    /// Lists a new table. Table files left behind under its name are not
+    /// reused; `Table::new` starts them afresh.
+    pub fn insert<S: Into<String>>(&mut self, table: S) -> io::Result<()> {
+        let name = table.into();
+        check_table_name(&name)?;
+        let path = self.table_path(&name);
+        let tbl_config = TableConfig { table: name, path, retention: None, partition: None };
+        self.append(serde_json::to_value(&tbl_config)?)
+    }
+
+    /// Records the table's retention policy.
+    ///
+    /// The table is listed again with the new policy; the last listing of a
+    /// table wins when the catalog is loaded.
+    pub fn set_retention(&mut self, table: &str, retention: Option<Retention>) -> io::Result<()> {
+        self.update(table, |config| config.retention = retention)
+    }
+
+    /// Records how the table is partitioned, listing it again like
+    /// `set_retention`.
+    pub fn set_partition(&mut self, table: &str, partition: Option<Partition>) -> io::Result<()> {
+        self.update(table, |config| config.partition = partition)
+    }
+
+    fn update<F: FnOnce(&mut TableConfig)>(&mut self, table: &str, change: F) -> io::Result<()> {
+        let path = self.table_path(table);
+        let mut tbl_config = match self.catalog.find(table) {
+            Some(index) => self.catalog.tables[index].clone(),
+            None => TableConfig { table: table.to_string(), path: path.clone(), retention: None, partition: None },
+        };
+        tbl_config.path = path;
+        change(&mut tbl_config);
+        self.append(serde_json::to_value(&tbl_config)?)
+    }
+
+    /// The partitioned tables and how each is partitioned.
+    pub fn partitions(&self) -> BTreeMap<String, Partition> {
+        self.catalog
+            .tables
+            .iter()
+            .filter_map(|config| Some((config.table.clone(), config.partition.clone()?)))
+            .collect()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The format new table and key logs are written in, known once loaded.
+    pub fn format(&self) -> LogFormat {
+        self.catalog.format.unwrap_or(LogFormat::Json)
+    }
+
+    /// Records the format new logs are written in as a `["format", name]`
+    /// marker; the last marker wins when the catalog is loaded.
+    pub fn set_format(&mut self, format: LogFormat) -> io::Result<()> {
+        self.append(serde_json::to_value(("format", format))?)
+    }
+
+    /// Reads the catalog, first finishing any drop or rename that a crash
+    /// interrupted, and lists the tables to load.
+    pub fn open_tables(&mut self) -> Res<Vec<TableSource>> {
+        self.catalog = Catalog::default();
+        for record in read_log(&mut self.file, &self.path, self.key.as_ref())?.1 {
+            self.catalog.apply(record)?;
+        }
+        self.seal = Sealer::resume(self.key.as_ref(), &mut self.file).map_err(|_| "cannot read log")?;
+        let path = |name: &str| {
+            if !is_valid_table_name(name) {
+                eprintln!("invalid table name {:?} in {:?}", name, self.path);
+                return Err("invalid table name in db config");
+            }
+            Ok(self.table_path(name))
+        };
+        for op in &self.catalog.pending {
+            let done = match op {
+                FileOp::Remove(name) => remove_table_files(&path(name)?),
+                FileOp::Move(from, to) => move_table_files(&path(from)?, &path(to)?),
+            };
+            done.map_err(|_| "cannot recover table files")?;
+        }
+        if self.catalog.stale {
+            self.compact().map_err(|_| "cannot compact db config")?;
+        }
+        let mut sources = Vec::with_capacity(self.catalog.tables.len());
+        for config in self.catalog.tables.clone() {
+            if !is_valid_table_name(&config.table) {
+                eprintln!("invalid table name {:?} in {:?}", config.table, self.path);
+                return Err("invalid table name in db config");
+            }
+            // tables are always stored under the root, whatever path was recorded
+            let path = self.table_path(&config.table);
+            sources.push(TableSource { name: config.table, path, retention: config.retention, key: self.key.clone() });
+        }
+        Ok(sources)
+    }
+
+    /// Undoes a batch that a crash interrupted: its operation is dropped from
+    /// the logs of the tables it wrote, and the tables it created are
+    /// dropped. Returns the tables left to load.
+    pub fn undo_batch(&mut self, batch: &Batch, sources: Vec<TableSource>) -> Res<Vec<TableSource>> {
+        eprintln!("undoing batch {} interrupted before it was committed", batch.seq);
+        for name in &batch.created {
+            let dropped = match self.catalog.find(name) {
+                Some(_) => self.remove_table(name),
+                None => remove_table_files(&self.table_path(name)),
+            };
+            dropped.map_err(|_| "cannot drop table of interrupted batch")?;
+        }
+        for name in batch.tables.iter().filter(|name| !batch.created.contains(name)) {
+            drop_operation(&self.table_path(name), batch.seq, self.key.as_ref())?;
+        }
+        Ok(sources.into_iter().filter(|source| !batch.created.contains(&source.name)).collect())
+    }
+
+    /// Drops a table. The drop is journaled before its files are removed,
+    /// and `load` finishes the removal if a crash interrupts it.
+    pub fn remove_table(&mut self, tbl_name: &str) -> io::Result<()> {
+        self.append(serde_json::to_value(("drop", tbl_name))?)?;
+        remove_table_files(&self.table_path(tbl_name))?;
+        self.compact()
+    }
+
+    /// Renames a table and its files. The rename is journaled before the
+    /// files are moved, and `load` finishes moving them if a crash
+    /// interrupts it.
+    pub fn rename_table(&mut self, from: &str, to: &str) -> io::Result<()> {
+        check_name(to)?;
+        let to_path = self.table_path(to);
+        // files of an earlier table of that name, dropped outside the catalog
+        remove_table_files(&to_path)?;
+        self.append(serde_json::to_value(("rename", from, to))?)?;
+        move_table_files(&self.table_path(from), &to_path)?;
+        self.compact()
+    }
 }
 
-#[derive(Debug)]
-pub struct DbConfig {
-    name: String,
-    root_path: PathBuf,
-    file: File,
+/// Writes `buf` to a temporary file beside `path` and renames it over `path`,
+/// so a crash leaves either the old or the new file intact.
+fn replace_file<P: AsRef<Path>>(path: P, buf: &[u8]) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    {
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(buf)?;
+        tmp.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    sync_dir(path)
 }
 
-impl DbConfig {
-    pub fn open<P: AsRef<Path>, S: Into<String>>(root: P, name: S) -> io::Result<Self> {
-        let mut root_path = PathBuf::new();
-        root_path.push(root);
-        let mut path = root_path.clone();
-        let name = name.into();
-        let test_db = name.clone() + ".db";
-        path.push(test_db);
-        let file = open_file(path)?;
-        Ok(Self {
-            name,
-            root_path,
-            file,
-        })
+/// Parses a `["checkpoint", 3]` style marker record.
+fn parse_marker(record: &JsonVal, tag: &str) -> Option<u64> {
+    match record.as_array().map(Vec::as_slice) {
+        Some([JsonVal::String(t), id, ..]) if t == tag => id.as_u64(),
+        _ => None,
     }
+}
 
-    pub fn insert<S: Into<String>>(&mut self, table: S) -> io::Result<()> {
-        let mut path = self.root_path.clone();
-        let name = table.into();
-        path.push(name.to_string() + ".table");
-        let tbl_config = TableConfig { table: name, path };
-        let line = serde_json::to_string(&tbl_config).unwrap() + "\n";
-        self.file.write_all(line.as_bytes())
-    }
-
-    pub fn load(&mut self) -> Res<Vec<Table>> {
-        let buf = Box::new(BufReader::new(&mut self.file));
-        let mut tables = Vec::new();
-        //TODO parallelize this
-        for line in buf.lines() {
-            let line = line.map_err(|_| "cannot read db config line")?;
-            let config: TableConfig =
-                serde_json::from_str(&line).map_err(|_| "cannot deserialize table config")?;
-            let table = Table::open(config.table, config.path).map_err(|_| "")?;
-            tables.push(table);
-        }
-        Ok(tables)
-    }
-
-    pub fn remove_table<S: Into<String>>(&mut self, tbl_name: S) -> io::Result<()> {
-        // create new meta file
-        let mut path_buf = self.root_path.clone();
-        let tbl_name = tbl_name.into();
-        let tmp_path = tbl_name.clone() + ".copy.table";
-        path_buf.push(&tmp_path);
-        let mut file = open_file(path_buf)?;
-        // read old meta file and write to new one minus the removed table
-        self.file.seek(SeekFrom::Start(0))?;
-        let buf = Box::new(BufReader::new(&mut self.file));
-        for line in buf.lines() {
-            let line = line?;
-            let config: TableConfig = serde_json::from_str(&line)?;
-            if config.table != tbl_name {
-                let json = serde_json::to_string(&config)? + "\n";
-                file.write_all(json.as_bytes())?;
-            }
-        }
-        // replace old meta file with new one
-        let mut old_path = self.root_path.clone();
-        old_path.push(tbl_name + ".table");
-        fs::copy(&tmp_path, old_path)?;
-        fs::remove_file(&tmp_path)?;
-        Ok(())
+/// When an operation was logged: the database's sequence number and the
+/// time in milliseconds since the unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stamp {
+    pub seq: u64,
+    pub at: u64,
+}
+
+impl Stamp {
+    pub fn now(seq: u64) -> Self {
+        Stamp { seq, at: now_millis() }
+    }
+
+    /// Parses the `["at", seq, millis]` record logged before each operation,
+    /// or the stamp a `["checkpoint", id, seq, millis]` marker carries over.
+    fn parse(record: &JsonVal, tag: &str) -> Option<Stamp> {
+        let (seq, at) = match record.as_array().map(Vec::as_slice) {
+            Some([JsonVal::String(t), seq, at]) if t == tag && tag == "at" => (seq, at),
+            Some([JsonVal::String(t), _, seq, at]) if t == tag && tag == "checkpoint" => (seq, at),
+            _ => return None,
+        };
+        Some(Stamp { seq: seq.as_u64()?, at: at.as_u64()? })
+    }
+
+    fn record(&self) -> JsonVal {
+        serde_json::json!(["at", self.seq, self.at])
     }
 }
 
-/// The replay log that records all mututations
+/// Parses a `["format", "bincode"]` catalog marker.
+fn parse_format(record: &JsonVal) -> Option<LogFormat> {
+    match record.as_array().map(Vec::as_slice) {
+        Some([JsonVal::String(t), format]) if t == "format" => format.as_str()?.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Writes a snapshot of a table's rows.
+///
+/// The first record is a `["snapshot", id]` marker followed by one per row.
+pub fn write_snapshot<P: AsRef<Path>>(path: P, format: LogFormat, key: Option<&Cipher>, id: u64, rows: &[Row]) -> io::Result<()> {
+    let (mut buf, mut seal) = encode_header(format, key)?;
+    buf.extend(encode(format, seal.as_mut(), &("snapshot", id))?);
+    for row in rows {
+        buf.extend(encode(format, seal.as_mut(), row)?);
+    }
+    replace_file(path, &buf)
+}
+
+/// Reads a snapshot written by `write_snapshot`, if there is one, first
+/// encrypting it if there is a key and it is not.
 ///
+/// Snapshots are renamed into place whole, so any damage is corruption.
+pub fn read_snapshot<P: AsRef<Path>>(path: P, key: Option<&Cipher>) -> Res<Option<(u64, Vec<Row>)>> {
+    let buf = match fs::read(&path) {
+        Ok(buf) => buf,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(_) => return Err("cannot open snapshot"),
+    };
+    let Scan { format, records, damage, sealed, .. } = scan_file(path.as_ref(), &buf, key)?;
+    let (id, rows) = snapshot_rows(path.as_ref(), records, damage)?;
+    if key.is_some() && !sealed {
+        eprintln!("encrypting {:?}", path.as_ref());
+        write_snapshot(&path, format, key, id, &rows).map_err(|_| "cannot encrypt snapshot")?;
+    }
+    Ok(Some((id, rows)))
+}
+
+/// Reads the rows of a snapshot from its records.
+fn snapshot_rows(path: &Path, records: Vec<JsonVal>, damage: Option<Damage>) -> Res<(u64, Vec<Row>)> {
+    if let Some(damage) = damage {
+        eprintln!("corrupt snapshot {:?}: {:?}", path, damage);
+        return Err("corrupt snapshot");
+    }
+    let mut records = records.into_iter();
+    let id = match records.next() {
+        Some(record) => parse_marker(&record, "snapshot").ok_or("bad snapshot")?,
+        None => return Err("bad snapshot"),
+    };
+    let mut rows = Vec::new();
+    for record in records {
+        match record {
+            JsonVal::Object(row) => rows.push(row),
+            _ => return Err("bad json"),
+        }
+    }
+    Ok((id, rows))
+}
+
+/// Reads the rows of a table's log from its records, along with the snapshot
+/// it follows on from and the last operation logged.
+fn log_rows(records: Vec<JsonVal>) -> Res<(Option<u64>, Option<Stamp>, Vec<Row>)> {
+    let (mut checkpoint, mut stamp) = (None, None);
+    let mut rows = Vec::new();
+    for (i, record) in records.into_iter().enumerate() {
+        if i == 0 {
+            checkpoint = parse_marker(&record, "checkpoint");
+            if checkpoint.is_some() {
+                stamp = Stamp::parse(&record, "checkpoint");
+                continue;
+            }
+        }
+        if let Some(at) = Stamp::parse(&record, "at") {
+            stamp = Some(at);
+            continue;
+        }
+        match record {
+            JsonVal::Object(row) => rows.push(row),
+            record => {
+                println!("{:?}", record);
+                return Err("bad json");
+            }
+        }
+    }
+    Ok((checkpoint, stamp, rows))
+}
+
+/// The replay log that records all mututations
 ///
+/// After a snapshot the log is truncated to a `["checkpoint", id]` marker
+/// naming the snapshot it follows on from.
 #[derive(Debug)]
 pub struct ReplayLog {
+    path: PathBuf,
     file: File,
+    // operations logged but not yet written to the file
+    pending: Mutex<Vec<u8>>,
+    dirty: AtomicBool,
+    checkpoint: Option<u64>,
+    format: LogFormat,
+    key: Option<Cipher>,
+    seal: Option<Sealer>,
+    // the last operation logged, known once replayed
+    stamp: Option<Stamp>,
 }
 
 impl ReplayLog {
-    pub fn new<P: AsRef<Path>>(path: P, rows: &[Map<String, JsonVal>]) -> io::Result<Self> {
-        let mut log = Self::open(path)?;
+    /// Starts a new log, discarding any left at `path` by a dropped table.
+    pub fn new<P: AsRef<Path>>(path: P, format: LogFormat, key: Option<&Cipher>, rows: &[Map<String, JsonVal>]) -> io::Result<Self> {
+        File::create(&path)?;
+        let mut log = Self::open_with(path, format, key)?;
         for row in rows {
             log.write(row)?;
         }
         Ok(log)
     }
 
-    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
-        let file = open_file(path)?;
-        Ok(Self { file })
+    /// Opens the log in whichever format it was written in, as JSON if new.
+    pub fn open<P: AsRef<Path>>(path: P, key: Option<&Cipher>) -> io::Result<Self> {
+        Self::open_with(path, LogFormat::Json, key)
+    }
+
+    fn open_with<P: AsRef<Path>>(path: P, format: LogFormat, key: Option<&Cipher>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (mut file, format) = open_log(&path, format, key)?;
+        let seal = Sealer::resume(key, &mut file)?;
+        Ok(Self {
+            path,
+            file,
+            pending: Mutex::default(),
+            dirty: AtomicBool::new(false),
+            checkpoint: None,
+            format,
+            key: key.cloned(),
+            seal,
+            stamp: None,
+        })
+    }
+
+    /// Writes out buffered operations and forces them to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        self.flush()?;
+        sync_file(&self.file, &self.dirty)
+    }
+
+    /// Hands buffered operations to the OS without waiting for the disk.
+    pub fn flush(&self) -> io::Result<()> {
+        flush_file(&self.file, &self.pending, &self.dirty)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn format(&self) -> LogFormat {
+        self.format
+    }
+
+    pub fn key(&self) -> Option<&Cipher> {
+        self.key.as_ref()
+    }
+
+    /// Points the log at the new path of its file after the file was moved.
+    pub fn moved<P: AsRef<Path>>(&mut self, path: P) {
+        self.path = path.as_ref().to_path_buf();
+    }
+
+    /// Rewrites the log in `format`.
+    pub fn convert(&mut self, format: LogFormat) -> io::Result<()> {
+        if format != self.format {
+            self.flush()?;
+            self.file = convert_log(&mut self.file, &self.path, format, self.key.as_ref())?;
+            self.seal = Sealer::resume(self.key.as_ref(), &mut self.file)?;
+            self.format = format;
+        }
+        Ok(())
+    }
+
+    /// The snapshot the log follows on from, known once it is replayed.
+    pub fn checkpoint(&self) -> Option<u64> {
+        self.checkpoint
+    }
+
+    /// The last operation logged.
+    pub fn stamp(&self) -> Option<Stamp> {
+        self.stamp
+    }
+
+    /// Stamps a change that is not logged as rows, such as a truncate, so
+    /// the next checkpoint carries it.
+    pub fn mark(&mut self, seq: u64) {
+        self.stamp = Some(Stamp::now(seq));
+    }
+
+    /// Empties the log, leaving only a marker for the snapshot `id` that
+    /// carries over the stamp of the last operation.
+    pub fn truncate(&mut self, id: u64) -> io::Result<()> {
+        let marker = match self.stamp {
+            Some(stamp) => serde_json::json!(["checkpoint", id, stamp.seq, stamp.at]),
+            None => serde_json::json!(["checkpoint", id]),
+        };
+        replace_file(&self.path, &encode_log(self.format, self.key.as_ref(), &[marker])?)?;
+        // anything still buffered is covered by the snapshot
+        self.pending.lock().unwrap().clear();
+        self.file = open_file(&self.path)?;
+        self.seal = Sealer::resume(self.key.as_ref(), &mut self.file)?;
+        self.checkpoint = Some(id);
+        Ok(())
     }
 
     pub fn insert(&mut self, vals: &[Map<String, JsonVal>]) -> io::Result<()> {
@@ -126,30 +1809,246 @@ impl ReplayLog {
         Ok(())
     }
 
+    /// Logs rows as one operation, stamped with its sequence number and time.
+    /// The operation is buffered whole until the next flush, so it is never
+    /// split.
+    pub fn append(&mut self, seq: u64, vals: &[Row]) -> io::Result<()> {
+        let stamp = Stamp::now(seq);
+        let mut buf = encode(self.format, self.seal.as_mut(), &stamp.record())?;
+        for val in vals {
+            buf.extend(encode(self.format, self.seal.as_mut(), val)?);
+        }
+        buffer(&self.file, &self.pending, &self.dirty, &buf)?;
+        self.stamp = Some(stamp);
+        Ok(())
+    }
+
     fn write(&mut self, val: &Map<String, JsonVal>) -> io::Result<()> {
-        let row = serde_json::to_string(val).unwrap() + "\n";
-        self.file.write_all(row.as_bytes())
+        let row = encode(self.format, self.seal.as_mut(), val)?;
+        buffer(&self.file, &self.pending, &self.dirty, &row)
     }
 
     pub fn replay(&mut self) -> Res<Vec<Row>> {
-        let buf = Box::new(BufReader::new(&mut self.file));
-        let mut rows = Vec::new();
-        //TODO parallelize this
-        for line in buf.lines() {
-            let line = line.map_err(|err| {
-                eprintln!("{:?}", err);
-                "bad line"
-            })?;
-            let row: Row = serde_json::from_str(&line).map_err(|err| {
-                println!("{:?}", err);
-                "bad json"
-            })?;
-            rows.push(row);
-        }
+        self.flush().map_err(|_| "cannot flush log")?;
+        let (_, records) = read_log(&mut self.file, &self.path, self.key.as_ref())?;
+        self.seal = Sealer::resume(self.key.as_ref(), &mut self.file).map_err(|_| "cannot read log")?;
+        let (checkpoint, stamp, rows) = log_rows(records)?;
+        self.checkpoint = checkpoint;
+        self.stamp = stamp;
         Ok(rows)
     }
 }
 
+/// A batch that writes more than one log, journaled in the key log.
+///
+/// A `["batch", seq, tables, created]` record listing the tables it writes
+/// and those it creates is logged before any of them is written, and a
+/// `["commit", seq]` marker once they all are. A batch with no marker is
+/// undone when the database is next opened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch {
+    pub seq: u64,
+    pub tables: Vec<String>,
+    pub created: Vec<String>,
+}
+
+impl Batch {
+    fn parse(record: &JsonVal) -> Option<Batch> {
+        match record.as_array().map(Vec::as_slice) {
+            Some([JsonVal::String(t), seq, tables, created]) if t == "batch" => Some(Batch {
+                seq: seq.as_u64()?,
+                tables: serde_json::from_value(tables.clone()).ok()?,
+                created: serde_json::from_value(created.clone()).ok()?,
+            }),
+            _ => None,
+        }
+    }
+
+    fn record(&self) -> JsonVal {
+        serde_json::json!(["batch", self.seq, self.tables, self.created])
+    }
+}
+
+/// A mutation of the key/value namespace, as recorded in the key log.
+///
+/// Serialized in the same shape as the client commands plus the version the
+/// write was given, e.g. `{"set":["k",1,7]}`. Records written before keys were
+/// versioned have no version and are read back as version 0.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum KeyOp {
+    #[serde(rename = "set")]
+    Set(String, JsonVal, #[serde(default)] u64),
+    #[serde(rename = "del")]
+    Del(String),
+    // expiry time in milliseconds since the unix epoch
+    #[serde(rename = "expire")]
+    Expire(String, u64),
+    #[serde(rename = "persist")]
+    Persist(String),
+}
+
+/// The replay log that records all key/value mutations
+#[derive(Debug)]
+pub struct KeyLog {
+    path: PathBuf,
+    file: File,
+    pending: Mutex<Vec<u8>>,
+    dirty: AtomicBool,
+    format: LogFormat,
+    key: Option<Cipher>,
+    seal: Option<Sealer>,
+    // the last operation logged, known once replayed
+    stamp: Option<Stamp>,
+}
+
+impl KeyLog {
+    /// Opens the log in whichever format it was written in, or in `format`
+    /// if it is new.
+    pub fn open<P: AsRef<Path>>(path: P, format: LogFormat, key: Option<&Cipher>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (mut file, format) = open_log(&path, format, key)?;
+        let seal = Sealer::resume(key, &mut file)?;
+        Ok(Self {
+            path,
+            file,
+            pending: Mutex::default(),
+            dirty: AtomicBool::new(false),
+            format,
+            key: key.cloned(),
+            seal,
+            stamp: None,
+        })
+    }
+
+    /// Writes out buffered operations and forces them to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        self.flush()?;
+        sync_file(&self.file, &self.dirty)
+    }
+
+    /// Hands buffered operations to the OS without waiting for the disk.
+    pub fn flush(&self) -> io::Result<()> {
+        flush_file(&self.file, &self.pending, &self.dirty)
+    }
+
+    #[cfg(test)]
+    pub fn format(&self) -> LogFormat {
+        self.format
+    }
+
+    /// Rewrites the log in `format`.
+    pub fn convert(&mut self, format: LogFormat) -> io::Result<()> {
+        if format != self.format {
+            self.flush()?;
+            self.file = convert_log(&mut self.file, &self.path, format, self.key.as_ref())?;
+            self.seal = Sealer::resume(self.key.as_ref(), &mut self.file)?;
+            self.format = format;
+        }
+        Ok(())
+    }
+
+    /// The last operation logged.
+    pub fn stamp(&self) -> Option<Stamp> {
+        self.stamp
+    }
+
+    /// Appends the ops as one operation stamped with its sequence number and
+    /// time, buffered whole so a batch is never split.
+    pub fn write(&mut self, seq: u64, ops: &[KeyOp]) -> io::Result<()> {
+        let stamp = Stamp::now(seq);
+        let mut buf = encode(self.format, self.seal.as_mut(), &stamp.record())?;
+        for op in ops {
+            buf.extend(encode(self.format, self.seal.as_mut(), op)?);
+        }
+        buffer(&self.file, &self.pending, &self.dirty, &buf)?;
+        self.stamp = Some(stamp);
+        Ok(())
+    }
+
+    /// Journals the start of a batch, writing it out, and syncing it too if
+    /// `sync`, before any of its tables is written.
+    pub fn begin(&mut self, batch: &Batch, sync: bool) -> io::Result<()> {
+        let buf = encode(self.format, self.seal.as_mut(), &batch.record())?;
+        buffer(&self.file, &self.pending, &self.dirty, &buf)?;
+        self.write_out(sync)
+    }
+
+    /// Logs the batch's key ops and marks it committed, once its tables are
+    /// written.
+    pub fn commit(&mut self, seq: u64, ops: &[KeyOp], sync: bool) -> io::Result<()> {
+        self.write(seq, ops)?;
+        let buf = encode(self.format, self.seal.as_mut(), &("commit", seq))?;
+        buffer(&self.file, &self.pending, &self.dirty, &buf)?;
+        self.write_out(sync)
+    }
+
+    /// Marks a batch as undone, so it is not undone again.
+    pub fn abort(&mut self, seq: u64) -> io::Result<()> {
+        let buf = encode(self.format, self.seal.as_mut(), &("abort", seq))?;
+        buffer(&self.file, &self.pending, &self.dirty, &buf)?;
+        self.write_out(true)
+    }
+
+    fn write_out(&self, sync: bool) -> io::Result<()> {
+        if sync {
+            self.sync()
+        } else {
+            self.flush()
+        }
+    }
+
+    /// Replays the ops, leaving out those of a batch that was never
+    /// committed. Returns that batch, if any, to be undone.
+    pub fn replay<F: FnMut(KeyOp)>(&mut self, mut apply: F) -> Res<Option<Batch>> {
+        self.flush().map_err(|_| "cannot flush log")?;
+        // the batch journaled last and not yet committed, with its ops
+        let mut open: Option<(Batch, Vec<KeyOp>)> = None;
+        for record in read_log(&mut self.file, &self.path, self.key.as_ref())?.1 {
+            if let Some(batch) = Batch::parse(&record) {
+                open = Some((batch, Vec::new()));
+                continue;
+            }
+            if let Some(seq) = parse_marker(&record, "commit") {
+                if let Some((_, ops)) = open.take_if(|(batch, _)| batch.seq == seq) {
+                    ops.into_iter().for_each(&mut apply);
+                }
+                continue;
+            }
+            if let Some(seq) = parse_marker(&record, "abort") {
+                open.take_if(|(batch, _)| batch.seq == seq);
+                continue;
+            }
+            if let Some(stamp) = Stamp::parse(&record, "at") {
+                self.stamp = Some(stamp);
+                continue;
+            }
+            let op: KeyOp = serde_json::from_value(record).map_err(|_| "bad json")?;
+            match open {
+                Some((ref batch, ref mut ops)) if self.stamp.is_some_and(|stamp| stamp.seq == batch.seq) => ops.push(op),
+                _ => apply(op),
+            }
+        }
+        self.seal = Sealer::resume(self.key.as_ref(), &mut self.file).map_err(|_| "cannot read log")?;
+        Ok(open.map(|(batch, _)| batch))
+    }
+}
+
+impl Drop for ReplayLog {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            eprintln!("error flushing {}; error = {:?}", self.path.display(), err);
+        }
+    }
+}
+
+impl Drop for KeyLog {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            eprintln!("error flushing {}; error = {:?}", self.path.display(), err);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::remove_file;
@@ -159,13 +2058,23 @@ mod tests {
 
     use super::*;
 
+    /// Loads the listed tables the way startup does, sorted by name.
+    fn load(log: &mut DbConfig) -> Vec<Table> {
+        let sources = log.open_tables().unwrap();
+        let tables = Mutex::new(Vec::new());
+        load_tables(sources, 2, |table| tables.lock().unwrap().push(table)).unwrap();
+        let mut tables = tables.into_inner().unwrap();
+        tables.sort_by(|a, b| a.name().cmp(b.name()));
+        tables
+    }
+
     #[test]
     fn dbconfig_load() {
-        let mut log = DbConfig::open("./", "test2").unwrap();
+        let mut log = DbConfig::open("./", "test2", None).unwrap();
         log.insert("a").unwrap();
         log.insert("b").unwrap();
         log.file.seek(SeekFrom::Start(0)).unwrap();
-        let tables = log.load().unwrap();
+        let tables = load(&mut log);
         assert_eq!(tables.len(), 2);
         assert_eq!(tables[0].name(), "a");
         assert_eq!(tables[0].len(), 0);
@@ -178,7 +2087,7 @@ mod tests {
 
     #[test]
     fn replaylog_load() {
-        let mut log = ReplayLog::open("./c.table").unwrap();
+        let mut log = ReplayLog::open("./c.table", None).unwrap();
         log.insert(&vec![obj!{"x"=> 1}, obj!{"x"=>"a"}])
             .unwrap();
         log.insert(&vec![obj!{"x"=>2}, obj!{"x"=>"b"}].as_ref())
@@ -197,4 +2106,370 @@ mod tests {
 
         remove_file("./c.table").unwrap();
     }
+
+    #[test]
+    fn replaylog_snapshot() {
+        let mut log = ReplayLog::open("./e.table", None).unwrap();
+        let rows = vec![obj!{"x"=>1}, obj!{"x"=>2}];
+        log.insert(&rows).unwrap();
+        write_snapshot("./e.snapshot", LogFormat::Json, None, 1, &rows).unwrap();
+        log.truncate(1).unwrap();
+        log.insert(&[obj!{"x"=>3}]).unwrap();
+
+        log.flush().unwrap();
+        let mut log = ReplayLog::open("./e.table", None).unwrap();
+        assert_eq!(log.replay().unwrap(), vec![obj!{"x"=>3}]);
+        assert_eq!(log.checkpoint(), Some(1));
+        assert_eq!(read_snapshot("./e.snapshot", None).unwrap(), Some((1, rows)));
+        assert_eq!(read_snapshot("./f.snapshot", None).unwrap(), None);
+
+        remove_file("./e.table").unwrap();
+        remove_file("./e.snapshot").unwrap();
+    }
+
+    #[test]
+    fn keylog_load() {
+        let mut log = KeyLog::open("./d.keys", LogFormat::Json, None).unwrap();
+        log.write(2, &[
+            KeyOp::Set("a".to_string(), JsonVal::from(1), 1),
+            KeyOp::Set("b".to_string(), JsonVal::from("x"), 2),
+        ])
+        .unwrap();
+        log.write(3, &[KeyOp::Del("a".to_string())]).unwrap();
+        log.flush().unwrap();
+        log.file.write_all(b"{\"set\":[\"c\",true]}\n").unwrap();
+        log.file.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut ops = Vec::new();
+        log.replay(|op| ops.push(op)).unwrap();
+        assert_eq!(
+            ops,
+            vec![
+                KeyOp::Set("a".to_string(), JsonVal::from(1), 1),
+                KeyOp::Set("b".to_string(), JsonVal::from("x"), 2),
+                KeyOp::Del("a".to_string()),
+                KeyOp::Set("c".to_string(), JsonVal::from(true), 0),
+            ]
+        );
+        assert_eq!(log.stamp().map(|s| s.seq), Some(3));
+
+        remove_file("./d.keys").unwrap();
+    }
+
+    #[test]
+    fn replaylog_torn_tail() {
+        let mut log = ReplayLog::open("./g.table", None).unwrap();
+        log.insert(&[obj!{"x"=>1}, obj!{"x"=>2}]).unwrap();
+        log.flush().unwrap();
+        let len = log.file.metadata().unwrap().len();
+        log.file.write_all(b"7:0badf00d:{\"x\":").unwrap();
+
+        log.flush().unwrap();
+        let mut log = ReplayLog::open("./g.table", None).unwrap();
+        assert_eq!(log.replay().unwrap(), vec![obj!{"x"=>1}, obj!{"x"=>2}]);
+        assert_eq!(log.file.metadata().unwrap().len(), len);
+        log.insert(&[obj!{"x"=>3}]).unwrap();
+
+        log.flush().unwrap();
+        let mut log = ReplayLog::open("./g.table", None).unwrap();
+        assert_eq!(log.replay().unwrap().len(), 3);
+
+        remove_file("./g.table").unwrap();
+    }
+
+    #[test]
+    fn replaylog_corrupt() {
+        let mut log = ReplayLog::open("./h.table", None).unwrap();
+        log.insert(&[obj!{"x"=>1}, obj!{"x"=>2}, obj!{"x"=>3}]).unwrap();
+        log.flush().unwrap();
+        let mut buf = fs::read("./h.table").unwrap();
+        let at = buf.windows(5).position(|w| w == b"\"x\":2").unwrap() + 4;
+        buf[at] = b'9';
+        fs::write("./h.table", &buf).unwrap();
+
+        let mut log = ReplayLog::open("./h.table", None).unwrap();
+        assert_eq!(log.replay(), Err("corrupt log record"));
+        assert!(repair_file("./h.table", None).unwrap() > 0);
+        let mut log = ReplayLog::open("./h.table", None).unwrap();
+        assert_eq!(log.replay().unwrap(), vec![obj!{"x"=>1}]);
+        assert_eq!(repair_file("./h.table", None).unwrap(), 0);
+
+        remove_file("./h.table").unwrap();
+    }
+
+    #[test]
+    fn replaylog_bincode() {
+        let rows = vec![obj!{"x"=>1}, obj!{"x"=>-2}, obj!{"x"=>2.5, "y"=>"s"}, obj!{"z"=>JsonVal::Null}];
+        let mut log = ReplayLog::new("./i.table", LogFormat::Bincode, None, &rows[..2]).unwrap();
+        log.insert(&rows[2..]).unwrap();
+        log.flush().unwrap();
+        let len = log.file.metadata().unwrap().len();
+        log.file.write_all(&[9, 0, 0, 0, 1, 2]).unwrap();
+
+        let mut log = ReplayLog::open("./i.table", None).unwrap();
+        assert_eq!(log.format(), LogFormat::Bincode);
+        assert_eq!(log.replay().unwrap(), rows);
+        assert_eq!(log.file.metadata().unwrap().len(), len);
+        assert!(fs::read("./i.table").unwrap().starts_with(BIN_MAGIC));
+
+        // a bad length in the middle claims to run past the end of the file
+        let mut buf = fs::read("./i.table").unwrap();
+        buf[BIN_MAGIC.len() + 4] = 0xff;
+        fs::write("./i.table", &buf).unwrap();
+        let mut log = ReplayLog::open("./i.table", None).unwrap();
+        assert_eq!(log.replay(), Err("corrupt log record"));
+        assert_eq!(fs::read("./i.table").unwrap(), buf);
+
+        remove_file("./i.table").unwrap();
+    }
+
+    #[test]
+    fn replaylog_zeroed_tail() {
+        let key = Cipher::new(&[4; 32]);
+        let logs = [
+            ("./zt_a.table", LogFormat::Bincode, None),
+            ("./zt_b.table", LogFormat::Json, Some(&key)),
+            ("./zt_c.table", LogFormat::Bincode, Some(&key)),
+        ];
+        for (path, format, key) in logs {
+            let log = ReplayLog::new(path, format, key, &[obj!{"x"=>1}, obj!{"x"=>2}]).unwrap();
+            log.flush().unwrap();
+            let len = log.file.metadata().unwrap().len();
+            let mut file = OpenOptions::new().append(true).open(path).unwrap();
+            file.write_all(&[0u8; 16]).unwrap();
+
+            let mut log = ReplayLog::open(path, key).unwrap();
+            assert_eq!(log.replay().unwrap(), vec![obj!{"x"=>1}, obj!{"x"=>2}]);
+            assert_eq!(fs::metadata(path).unwrap().len(), len);
+            log.insert(&[obj!{"x"=>3}]).unwrap();
+            log.flush().unwrap();
+            let mut log = ReplayLog::open(path, key).unwrap();
+            assert_eq!(log.replay().unwrap().len(), 3);
+
+            remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn replaylog_tampered() {
+        let key = Cipher::new(&[3; 32]);
+        let log = ReplayLog::new("./m.table", LogFormat::Json, Some(&key), &[obj!{"x"=>1}, obj!{"x"=>2}]).unwrap();
+        log.flush().unwrap();
+        let mut buf = fs::read("./m.table").unwrap();
+        let (mut pos, mut last) = (SEALED_HEADER, SEALED_HEADER);
+        while let Ok((_, len)) = decode_frame(&buf[pos..]) {
+            last = pos;
+            pos += len;
+        }
+        // flip a byte of the last record and give it a matching CRC
+        buf[pos - 1] ^= 1;
+        let crc = crc32fast::hash(&buf[last + 8..pos]);
+        buf[last + 4..last + 8].copy_from_slice(&crc.to_le_bytes());
+        fs::write("./m.table", &buf).unwrap();
+
+        let mut log = ReplayLog::open("./m.table", Some(&key)).unwrap();
+        assert_eq!(log.replay(), Err("corrupt log record"));
+        assert_eq!(fs::read("./m.table").unwrap(), buf);
+
+        // records that are intact but swapped, or copied from another file
+        let log = ReplayLog::new("./m.table", LogFormat::Json, Some(&key), &[obj!{"x"=>1}, obj!{"x"=>2}]).unwrap();
+        log.flush().unwrap();
+        let buf = fs::read("./m.table").unwrap();
+        let len = decode_frame(&buf[SEALED_HEADER..]).unwrap().1;
+        let (first, second) = buf[SEALED_HEADER..].split_at(len);
+        fs::write("./m.table", [&buf[..SEALED_HEADER], second, first].concat()).unwrap();
+        let mut log = ReplayLog::open("./m.table", Some(&key)).unwrap();
+        assert_eq!(log.replay(), Err("corrupt log record"));
+        let other = ReplayLog::new("./n.table", LogFormat::Json, Some(&key), &[obj!{"x"=>3}, obj!{"x"=>4}]).unwrap();
+        other.flush().unwrap();
+        let other = fs::read("./n.table").unwrap();
+        fs::write("./m.table", [&buf[..SEALED_HEADER], &other[SEALED_HEADER..]].concat()).unwrap();
+        let mut log = ReplayLog::open("./m.table", Some(&key)).unwrap();
+        assert_eq!(log.replay(), Err("corrupt log record"));
+
+        remove_file("./m.table").unwrap();
+        remove_file("./n.table").unwrap();
+    }
+
+    #[test]
+    fn last_stamp_ok() {
+        let key = Cipher::new(&[5; 32]);
+        let rows: Vec<Row> = (0..2000).map(|i| obj!{"x" => i, "pad" => "enough to run past the first tail window"}).collect();
+        let logs = [
+            ("./ls_a.table", LogFormat::Json, None),
+            ("./ls_b.table", LogFormat::Bincode, None),
+            ("./ls_c.table", LogFormat::Json, Some(&key)),
+            ("./ls_d.table", LogFormat::Bincode, Some(&key)),
+        ];
+        for (path, format, key) in logs {
+            let mut log = ReplayLog::new(path, format, key, &[]).unwrap();
+            let source = TableSource::new("ls".to_string(), PathBuf::from(path), None, key.cloned());
+            assert_eq!(source.last_stamp(), None);
+            log.append(7, &rows[..1]).unwrap();
+            log.append(9, &rows).unwrap();
+            log.flush().unwrap();
+            assert_eq!(source.last_stamp().map(|stamp| stamp.seq), Some(9));
+            // a torn tail makes it scan the whole log instead
+            log.file.write_all(&[9, 0, 0]).unwrap();
+            assert_eq!(source.last_stamp().map(|stamp| stamp.seq), Some(9));
+            remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn replaylog_convert() {
+        let rows = vec![obj!{"x"=>1}, obj!{"x"=>"y"}];
+        let mut log = ReplayLog::new("./j.table", LogFormat::Json, None, &rows).unwrap();
+        log.convert(LogFormat::Bincode).unwrap();
+        log.insert(&[obj!{"x"=>3}]).unwrap();
+
+        log.flush().unwrap();
+        let mut log = ReplayLog::open("./j.table", None).unwrap();
+        assert_eq!(log.format(), LogFormat::Bincode);
+        assert_eq!(log.replay().unwrap(), vec![obj!{"x"=>1}, obj!{"x"=>"y"}, obj!{"x"=>3}]);
+        log.convert(LogFormat::Json).unwrap();
+        log.flush().unwrap();
+        let mut log = ReplayLog::open("./j.table", None).unwrap();
+        assert_eq!(log.format(), LogFormat::Json);
+        assert_eq!(log.replay().unwrap().len(), 3);
+
+        remove_file("./j.table").unwrap();
+    }
+
+    #[test]
+    fn legacy_log_migrated() {
+        fs::write("./k.table", "{\"x\":1}\n{\"x\":2}\n").unwrap();
+        let mut log = ReplayLog::open("./k.table", None).unwrap();
+        assert_eq!(log.replay().unwrap(), vec![obj!{"x"=>1}, obj!{"x"=>2}]);
+        log.insert(&[obj!{"x"=>3}]).unwrap();
+        assert!(fs::read_to_string("./k.table").unwrap().starts_with("12:"));
+
+        log.flush().unwrap();
+        let mut log = ReplayLog::open("./k.table", None).unwrap();
+        assert_eq!(log.replay().unwrap().len(), 3);
+
+        remove_file("./k.table").unwrap();
+    }
+
+    #[test]
+    fn future_version_refused() {
+        let mut buf = encode(LogFormat::Json, None, &("memson", FORMAT_VERSION + 1)).unwrap();
+        buf.extend(encode(LogFormat::Json, None, &obj!{"x"=>1}).unwrap());
+        fs::write("./l.table", &buf).unwrap();
+        let mut log = ReplayLog::open("./l.table", None).unwrap();
+        assert_eq!(log.replay(), Err("unsupported format version"));
+        assert_eq!(fs::read("./l.table").unwrap(), buf);
+
+        fs::write("./l.table", [BIN_MAGIC, &[FORMAT_VERSION as u8 + 1]].concat()).unwrap();
+        let mut log = ReplayLog::open("./l.table", None).unwrap();
+        assert_eq!(log.replay(), Err("unsupported format version"));
+
+        remove_file("./l.table").unwrap();
+    }
+
+    #[test]
+    fn dbconfig_drop_rename() {
+        let mut log = DbConfig::open("./", "cat", None).unwrap();
+        load(&mut log);
+        log.insert("cat_a").unwrap();
+        log.insert("cat_b").unwrap();
+        ReplayLog::new("./cat_a.table", LogFormat::Json, None, &[obj!{"x"=>1}]).unwrap();
+        ReplayLog::new("./cat_b.table", LogFormat::Json, None, &[obj!{"x"=>2}]).unwrap();
+        log.remove_table("cat_a").unwrap();
+        assert!(!Path::new("./cat_a.table").exists());
+        log.rename_table("cat_b", "cat_c").unwrap();
+        assert!(!Path::new("./cat_b.table").exists());
+
+        let mut log = DbConfig::open("./", "cat", None).unwrap();
+        let tables = load(&mut log);
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].name(), "cat_c");
+        assert_eq!(tables[0].rows(), &[obj!{"x"=>2}]);
+        // journal records are folded away
+        let (_, records) = read_log(&mut log.file, &log.path, None).unwrap();
+        assert_eq!(records.len(), 2);
+
+        remove_file("./cat.db").unwrap();
+        remove_file("./cat_c.table").unwrap();
+    }
+
+    #[test]
+    fn dbconfig_recovers_interrupted_ops() {
+        let mut log = DbConfig::open("./", "crash", None).unwrap();
+        load(&mut log);
+        log.insert("crash_a").unwrap();
+        log.insert("crash_b").unwrap();
+        ReplayLog::new("./crash_a.table", LogFormat::Json, None, &[obj!{"x"=>1}]).unwrap();
+        ReplayLog::new("./crash_b.table", LogFormat::Json, None, &[obj!{"x"=>2}]).unwrap();
+        // journal the changes as if a crash hit before the files were touched
+        log.file.write_all(&encode(LogFormat::Json, None, &("drop", "crash_a")).unwrap()).unwrap();
+        log.file.write_all(&encode(LogFormat::Json, None, &("rename", "crash_b", "crash_c")).unwrap()).unwrap();
+
+        let mut log = DbConfig::open("./", "crash", None).unwrap();
+        let tables = load(&mut log);
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].name(), "crash_c");
+        assert_eq!(tables[0].rows(), &[obj!{"x"=>2}]);
+        assert!(!Path::new("./crash_a.table").exists());
+        assert!(!Path::new("./crash_b.table").exists());
+
+        remove_file("./crash.db").unwrap();
+        remove_file("./crash_c.table").unwrap();
+    }
+
+    #[test]
+    fn dbconfig_ignores_recorded_paths() {
+        fs::create_dir_all("./moved_db").unwrap();
+        let mut log = DbConfig::open("./moved_db", "moved", None).unwrap();
+        load(&mut log);
+        // a data dir copied from elsewhere still records the old paths
+        for name in ["moved_a", "moved_b"] {
+            log.insert(name).unwrap();
+            let config = TableConfig { table: name.to_string(), path: PathBuf::from(format!("./{}.table", name)), retention: None, partition: None };
+            log.append(serde_json::to_value(&config).unwrap()).unwrap();
+            ReplayLog::new(format!("./{}.table", name), LogFormat::Json, None, &[obj!{"x"=>1}]).unwrap();
+            ReplayLog::new(format!("./moved_db/{}.table", name), LogFormat::Json, None, &[obj!{"x"=>2}]).unwrap();
+        }
+        log.file.write_all(&encode(LogFormat::Json, None, &("drop", "moved_a")).unwrap()).unwrap();
+        log.file.write_all(&encode(LogFormat::Json, None, &("rename", "moved_b", "moved_c")).unwrap()).unwrap();
+
+        let mut log = DbConfig::open("./moved_db", "moved", None).unwrap();
+        let tables = load(&mut log);
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].name(), "moved_c");
+        assert_eq!(tables[0].rows(), &[obj!{"x"=>2}]);
+        assert!(!Path::new("./moved_db/moved_a.table").exists());
+        assert!(Path::new("./moved_a.table").exists());
+        assert!(Path::new("./moved_b.table").exists());
+        assert!(!Path::new("./moved_c.table").exists());
+
+        remove_file("./moved_a.table").unwrap();
+        remove_file("./moved_b.table").unwrap();
+        fs::remove_dir_all("./moved_db").unwrap();
+    }
+
+    #[test]
+    fn repair_ignores_recorded_paths() {
+        fs::create_dir_all("./repair_db").unwrap();
+        let mut log = DbConfig::open("./repair_db", "repair", None).unwrap();
+        log.insert("rp_a").unwrap();
+        let config = TableConfig { table: "rp_a".to_string(), path: PathBuf::from("./rp_a.table"), retention: None, partition: None };
+        log.append(serde_json::to_value(&config).unwrap()).unwrap();
+        drop(log);
+        for path in ["./rp_a.table", "./repair_db/rp_a.table"] {
+            let mut log = ReplayLog::new(path, LogFormat::Json, None, &[obj!{"x"=>1}]).unwrap();
+            log.flush().unwrap();
+            log.file.write_all(b"7:0badf00d:{\"x\":").unwrap();
+        }
+        let outside = fs::read("./rp_a.table").unwrap();
+
+        let report = repair("./repair_db", "repair", None).unwrap();
+        let (path, dropped) = report.last().unwrap();
+        assert_eq!(path, &Path::new("./repair_db").join("rp_a.table"));
+        assert!(*dropped > 0);
+        assert_eq!(fs::read("./rp_a.table").unwrap(), outside);
+
+        remove_file("./rp_a.table").unwrap();
+        fs::remove_dir_all("./repair_db").unwrap();
+    }
 }
@@ -7,13 +7,18 @@
 
 
 use std::error::Error;
-use std::sync::{Arc, Mutex};
+use std::fmt::Debug;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use clap::{App, Arg};
-use futures::{SinkExt, StreamExt};
+use clap::{App, Arg, SubCommand};
+use futures::{SinkExt, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::{Value as JsonVal, Map};
 use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task;
 use tokio_util::codec::{Framed, LinesCodec};
 
 #[macro_export]
@@ -29,9 +34,18 @@ macro_rules! obj (
      };
 );
 
+use config::Config;
+use crypt::Cipher;
 use db::*;
+use import::{FileFormat, ImportOptions};
+use json::{eval_json_query, parse_batch, parse_json_val, Cmd, BAD_BATCH};
+use log::{load_tables, Durability, RecoveryPoint};
 
+mod config;
+mod crypt;
 mod db;
+mod export;
+mod import;
 mod json;
 mod log;
 mod query;
@@ -63,8 +77,13 @@ enum Request {
 }
 
 /// Responses to the `Request` commands above
+///
+/// The `id` is echoed back from the request envelope, if one was given, so
+/// clients can match responses that arrive out of order.
+#[derive(Debug)]
 enum Response {
-    Value { value: JsonVal },
+    Value { id: Option<JsonVal>, value: JsonVal },
+    Error { id: Option<JsonVal>, msg: &'static str },
 }
 
 #[tokio::main]
@@ -74,12 +93,22 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .about("In-memory JSON Cache")
         .author("jaupe")
         .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("Reads settings from a TOML config file")
+                .takes_value(true),
+        )
+        .arg(
+            // the name the config file flag had before `--config`
             Arg::with_name("log")
                 .short("l")
                 .long("log")
                 .value_name("FILE")
-                .help("Sets a custom config file")
-                .takes_value(false),
+                .conflicts_with("config")
+                .hidden(true)
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("host")
@@ -87,27 +116,227 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .long("host")
                 .value_name("IP")
                 .help("Sets the IP address to listen on")
-                .takes_value(false),
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("port")
                 .short("p")
                 .long("port")
-                .value_name("port")
+                .value_name("PORT")
                 .help("Sets the port number to listen on")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("data-dir")
+                .long("data-dir")
+                .value_name("DIR")
+                .help("Sets the directory the database files are kept in")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("db-name")
+                .long("db-name")
+                .value_name("NAME")
+                .help("Sets the name of the database")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("durability")
+                .short("d")
+                .long("durability")
+                .value_name("POLICY")
+                .help("Sets when writes are synced to disk")
+                .possible_values(&["always", "every-second", "os"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .help("Sets the format new table and key logs are written in")
+                .possible_values(&["json", "bincode"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("encryption-key")
+                .long("encryption-key")
+                .value_name("FILE")
+                .help("Encrypts every database file with the key in FILE, 32 bytes or 64 hex digits")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("import-dir")
+                .long("import-dir")
+                .value_name("DIR")
+                .help("Sets the directory clients import files from, relative to the data directory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("export-dir")
+                .long("export-dir")
+                .value_name("DIR")
+                .help("Sets the directory clients export files to, relative to the data directory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("backup-dir")
+                .long("backup-dir")
+                .value_name("DIR")
+                .help("Sets the directory clients write backups to, relative to the data directory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("load-threads")
+                .long("load-threads")
+                .value_name("N")
+                .help("Sets how many tables are loaded at once on startup, 0 for one per CPU")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("load-in-background")
+                .long("load-in-background")
+                .help("Accepts connections while tables load; commands on them fail until loaded")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("lazy-tables")
+                .long("lazy-tables")
+                .help("Leaves tables on disk until they are first used")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("table-memory")
+                .long("table-memory")
+                .value_name("BYTES")
+                .help("Evicts the least recently used tables to disk once tables take more memory than this")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("maxmemory")
+                .long("maxmemory")
+                .value_name("BYTES")
+                .help("Caps the memory keys and tables may take together")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("maxmemory-policy")
+                .long("maxmemory-policy")
+                .value_name("POLICY")
+                .help("Sets which keys are evicted once memory is over maxmemory")
+                .possible_values(&["noeviction", "allkeys-lru", "allkeys-lfu", "volatile-ttl"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("convert")
+                .long("convert")
+                .help("Rewrites every log in the log format and exits")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("repair")
+                .long("repair")
+                .help("Truncates damaged log records and exits")
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("restore")
+                .long("restore")
+                .value_name("DIR")
+                .help("Restores the database from a backup before starting")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("recover-to")
+                .long("recover-to")
+                .value_name("POINT")
+                .help("Rolls the database back to seq:N or time:MILLIS before starting")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("recover-only")
+                .long("recover-only")
+                .requires("recover-to")
+                .help("Exits once the database is recovered")
+                .takes_value(false),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Imports a CSV, NDJSON or Parquet file into a table and exits")
+                .arg(Arg::with_name("TABLE").help("The table to insert into").required(true).index(1))
+                .arg(Arg::with_name("FILE").help("The file to read").required(true).index(2))
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Sets the file format, guessed from the extension if not given")
+                        .possible_values(&["csv", "ndjson", "parquet"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("delimiter")
+                        .long("delimiter")
+                        .value_name("CHAR")
+                        .help("Sets the CSV field separator")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("no-header")
+                        .long("no-header")
+                        .help("Reads the first CSV line as a row rather than column names")
+                        .takes_value(false),
+                ),
+        )
         .get_matches();
 
-    let log = matches.value_of("log").unwrap_or("log.memson");
+    // settings from the config file, overridden by any given as flags
+    let mut config = match matches.value_of("config").or_else(|| matches.value_of("log")) {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    for key in config::KEYS {
+        if let Some(val) = matches.value_of(key) {
+            config.set(key, val)?;
+        }
+    }
+    if matches.is_present("load-in-background") {
+        config.load_in_background = true;
+    }
+    if matches.is_present("lazy-tables") {
+        config.lazy_tables = true;
+    }
+    print!("{}", config.to_toml());
+    fs::create_dir_all(&config.data_dir)?;
+    let key = match &config.encryption_key {
+        Some(path) => Some(Cipher::load(path)?),
+        None => None,
+    };
+    let key = key.as_ref();
+
+    if matches.is_present("repair") {
+        for (path, dropped) in log::repair(&config.data_dir, &config.db_name, key)? {
+            println!("{:?}: dropped {} bytes", path, dropped);
+        }
+        return Ok(());
+    }
+    if let Some(dir) = matches.value_of("restore") {
+        for path in log::restore(dir, &config.data_dir, &config.db_name, key)? {
+            println!("restored {:?}", path);
+        }
+    }
+    if let Some(point) = matches.value_of("recover-to") {
+        let point: RecoveryPoint = point.parse()?;
+        for (path, dropped) in log::recover(&config.data_dir, &config.db_name, point, key)? {
+            println!("{:?}: dropped {} records", path, dropped);
+        }
+        if matches.is_present("recover-only") {
+            return Ok(());
+        }
+    }
+
     // Parse the address we're going to run this server on
     // and set up our TCP listener to accept connections.
-    let host = matches.value_of("host").unwrap_or("127.0.0.1");
-    let port = matches.value_of("port").unwrap_or("8000");
-    let addr = host.to_string() + ":" + port;
-    println!("replaying log: {:?}", log);
-    println!("listening on: {:?}", addr);
-    let mut listener = TcpListener::bind(&addr).await?;
+    let addr = config.addr();
+    let durability = config.durability;
 
     // Create the shared state of this server that will be shared amongst all
     // clients. We populate the initial database and then create the `Database`
@@ -115,8 +344,127 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // each independently spawned client will have a reference to the in-memory
     // database.
 
-    let db: Database = Database::open("./", "db").unwrap();
-    let db: Arc<Mutex<Database>> = Arc::new(Mutex::new(db));
+    // converting rewrites every table, and importing exits before a
+    // background load could finish, so both need the tables loaded first
+    let converting = matches.is_present("convert") || matches.subcommand_matches("import").is_some();
+    let (mut db, mut sources) = if (config.load_in_background || config.lazy_tables) && !converting {
+        Database::open_deferred(&config.data_dir, config.db_name.as_str(), key)?
+    } else {
+        (Database::open_with(&config.data_dir, config.db_name.as_str(), config.load_threads, key)?, Vec::new())
+    };
+    if config.lazy_tables {
+        db.keep_cold(std::mem::take(&mut sources));
+    }
+    if let Some(format) = config.log_format {
+        db.set_log_format(format)?;
+    }
+    if matches.is_present("convert") {
+        db.convert_logs()?;
+        println!("converted logs to {:?}", db.log_format());
+        return Ok(());
+    }
+    if let Some(import) = matches.subcommand_matches("import") {
+        let mut opts = ImportOptions {
+            format: import.value_of("format").map(str::parse::<FileFormat>).transpose()?,
+            header: !import.is_present("no-header"),
+            ..ImportOptions::default()
+        };
+        if let Some(delimiter) = import.value_of("delimiter") {
+            let mut chars = delimiter.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => opts.delimiter = Some(c),
+                _ => return Err("delimiter must be a single character".into()),
+            }
+        }
+        let (table, file) = (import.value_of("TABLE").unwrap(), import.value_of("FILE").unwrap());
+        let report = db.import(table.to_string(), file, &opts)?;
+        db.sync()?;
+        println!("imported {} rows into {}, rejected {} lines", report.rows, table, report.rejected);
+        if !report.lines.is_empty() {
+            println!("rejected lines: {:?}", report.lines);
+        }
+        return Ok(());
+    }
+    db.set_durability(durability);
+    db.set_import_dir(config.data_dir.join(&config.import_dir));
+    db.set_export_dir(config.data_dir.join(&config.export_dir));
+    db.set_backup_dir(config.data_dir.join(&config.backup_dir));
+    db.set_table_budget(config.table_memory)?;
+    db.set_max_memory(config.maxmemory, config.maxmemory_policy);
+    let mut listener = TcpListener::bind(&addr).await?;
+    let db: Arc<RwLock<Database>> = Arc::new(RwLock::new(db));
+
+    if !sources.is_empty() {
+        let loader = db.clone();
+        let threads = config.load_threads;
+        std::thread::spawn(move || {
+            if let Err(e) = load_tables(sources, threads, |table| loader.write().unwrap().loaded(table)) {
+                // carrying on would serve the table as if it were missing
+                eprintln!("error loading tables; error = {:?}", e);
+                std::process::exit(1);
+            }
+        });
+    }
+
+    if durability == Durability::EverySecond {
+        let flusher = db.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                // syncing only needs a shared lock, so it never blocks readers,
+                // but it waits on the disk, so it runs off the async workers
+                let flusher = flusher.clone();
+                let res = task::spawn_blocking(move || flusher.read().unwrap().sync()).await;
+                if let Err(e) = res.unwrap_or_else(|e| Err(e.into())) {
+                    eprintln!("error syncing logs; error = {:?}", e);
+                }
+            }
+        });
+    }
+
+    // Expired keys are hidden from readers straight away but only freed here.
+    // Like the pruner below, it takes the write lock and waits on the disk,
+    // so it runs off the async workers.
+    let sweeper = db.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let sweeper = sweeper.clone();
+            let res = task::spawn_blocking(move || {
+                let mut db = sweeper.write().unwrap();
+                db.remove_expired();
+                db.commit_logs()
+            })
+            .await;
+            if let Err(e) = res.unwrap_or_else(|e| Err(e.into())) {
+                eprintln!("error syncing logs; error = {:?}", e);
+            }
+        }
+    });
+
+    // Age-based retention drops rows over time, not just on insert.
+    let pruner = db.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            let pruner = pruner.clone();
+            let res = task::spawn_blocking(move || {
+                let mut db = pruner.write().unwrap();
+                if let Err(e) = db.enforce_retention() {
+                    eprintln!("error enforcing retention; error = {:?}", e);
+                }
+                db.commit_logs()
+            })
+            .await;
+            if let Err(e) = res.unwrap_or_else(|e| Err(e.into())) {
+                eprintln!("error syncing logs; error = {:?}", e);
+            }
+        }
+    });
+
     loop {
         match listener.accept().await {
             Ok((socket, _)) => {
@@ -132,35 +480,24 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     // Since our protocol is line-based we use `tokio_codecs`'s `LineCodec`
                     // to convert our stream of bytes, `socket`, into a `Stream` of lines
                     // as well as convert our line based responses into a stream of bytes.
-                    let mut lines = Framed::new(socket, LinesCodec::new());
-
-                    // Here for every line we get back from the `Framed` decoder,
-                    // we parse the request, and if it's valid we generate a response
-                    // based on the values in the database.
-                    while let Some(result) = lines.next().await {
-                        match result {
-                            Ok(line) => {
-                                let response = match handle_request(&line, &dbase) {
-                                    Ok(r) => r,
-                                    Err(err) => {
-                                        eprintln!("{:?}", err);
-                                        continue;
-                                    }
-                                };
-
-                                let response = response.serialize();
-
-                                if let Err(e) = lines.send(response).await {
-                                    println!("error on sending response; error = {:?}", e);
-                                }
-                            }
-                            Err(e) => {
-                                println!("error on decoding from socket; error = {:?}", e);
+                    let (mut sink, lines) = Framed::new(socket, LinesCodec::new()).split();
+
+                    // Responses are funnelled through a channel to a single writer so that
+                    // requests carrying an id can complete in any order.
+                    let (tx, mut rx) = mpsc::unbounded_channel::<Response>();
+                    let writer = tokio::spawn(async move {
+                        while let Some(response) = rx.recv().await {
+                            if let Err(e) = sink.send(response.serialize()).await {
+                                println!("error on sending response; error = {:?}", e);
+                                break;
                             }
                         }
-                    }
+                    });
+
+                    serve(lines, tx, dbase).await;
 
                     // The connection will be closed at this point as `lines.next()` has returned `None`.
+                    let _ = writer.await;
                 });
             }
             Err(e) => println!("error accepting socket; error = {:?}", e),
@@ -168,25 +505,397 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }
 }
 
-fn handle_request(line: &str, db_lock: &Arc<Mutex<Database>>) -> Res<Response> {
-    let mut db = db_lock.lock().unwrap();
-    let val = db.eval(line);
-    let val = match val {
-        Ok(val) => Response::Value { value: val },
-        Err(msg) => {
-            eprintln!("error: {}", msg);
-            Response::Value {
-                value: JsonVal::Null,
+/// Answers the requests read from one connection, sending the responses to `tx`.
+///
+/// Read-only commands that carry an id are evaluated concurrently and may be
+/// answered out of order. Everything else waits for the requests before it,
+/// so a client always sees its own writes and its writes apply in order.
+async fn serve<S, E>(mut lines: S, tx: mpsc::UnboundedSender<Response>, dbase: Arc<RwLock<Database>>)
+where
+    S: Stream<Item = Result<String, E>> + Unpin,
+    E: Debug,
+{
+    let mut session = Session::default();
+    // every concurrent read holds a sender, so once they are all dropped
+    // the receiver yields `None` and the reads have been answered
+    let (mut reading, mut read) = mpsc::channel::<()>(1);
+
+    // Here for every line we get back from the `Framed` decoder,
+    // we parse the request, and if it's valid we generate a response
+    // based on the values in the database.
+    while let Some(result) = lines.next().await {
+        match result {
+            Ok(line) => {
+                let (id, cmd) = parse_request(&line);
+                let step = match cmd {
+                    Ok(cmd) => session.handle(cmd, &dbase).await,
+                    Err(err) => Step::Reply(Err(err)),
+                };
+                if id.is_some() && step.is_read() {
+                    let (dbase, tx, reading) = (dbase.clone(), tx.clone(), reading.clone());
+                    tokio::spawn(async move {
+                        let _ = tx.send(Response::new(id, step.run(&dbase).await));
+                        drop(reading);
+                    });
+                } else {
+                    // a write must not be seen by reads sent before it
+                    drop(reading);
+                    let _ = read.recv().await;
+                    let (next, rx) = mpsc::channel(1);
+                    reading = next;
+                    read = rx;
+                    let _ = tx.send(Response::new(id, step.run(&dbase).await));
+                }
             }
+            Err(e) => {
+                println!("error on decoding from socket; error = {:?}", e);
+            }
+        }
+    }
+}
+
+/// Splits a request line into its optional id and the command it wraps.
+///
+/// A request is either a bare command, e.g. `{"get": "k1"}`, or an envelope
+/// of the form `{"id": 1, "cmd": {"get": "k1"}}`. A JSON array of commands
+/// is applied as one atomic batch.
+fn parse_request(line: &str) -> (Option<JsonVal>, Res<Cmd>) {
+    let val: JsonVal = match serde_json::from_str(line) {
+        Ok(val) => val,
+        Err(_) => return (None, Err("bad json")),
+    };
+    match val {
+        JsonVal::Object(mut obj) if obj.contains_key("id") => {
+            let id = obj.remove("id");
+            let cmd = match obj.remove("cmd") {
+                Some(cmd) if obj.is_empty() => parse_cmd(cmd),
+                _ => Err("bad request"),
+            };
+            (id, cmd)
+        }
+        val => (None, parse_cmd(val)),
+    }
+}
+
+fn parse_cmd(val: JsonVal) -> Res<Cmd> {
+    match val {
+        JsonVal::Array(arr) => parse_batch(arr),
+        val => parse_json_val(val),
+    }
+}
+
+/// The transaction state of a single connection.
+#[derive(Debug, Default)]
+struct Session {
+    // commands queued since `multi`, if one is open
+    queue: Option<Vec<Cmd>>,
+    // watched keys and the write sequence seen when they were watched
+    watches: Vec<(String, u64)>,
+}
+
+/// What to do with a request once the session has seen it.
+enum Step {
+    Reply(Res<JsonVal>),
+    Eval(Cmd),
+    Exec(Vec<Cmd>, Vec<(String, u64)>),
+}
+
+impl Session {
+    async fn handle(&mut self, cmd: Cmd, db_lock: &Arc<RwLock<Database>>) -> Step {
+        match cmd {
+            Cmd::Multi => {
+                if self.queue.is_some() {
+                    return Step::Reply(Err("multi calls can not be nested"));
+                }
+                self.queue = Some(Vec::new());
+                Step::Reply(Ok(JsonVal::from("OK")))
+            }
+            Cmd::Exec => match self.queue.take() {
+                Some(cmds) => Step::Exec(cmds, self.watches.drain(..).collect()),
+                None => Step::Reply(Err("exec without multi")),
+            },
+            Cmd::Discard => match self.queue.take() {
+                Some(_) => {
+                    self.watches.clear();
+                    Step::Reply(Ok(JsonVal::from("OK")))
+                }
+                None => Step::Reply(Err("discard without multi")),
+            },
+            Cmd::Watch(keys) => {
+                if self.queue.is_some() {
+                    return Step::Reply(Err("watch inside multi is not allowed"));
+                }
+                Step::Reply(self.watch(keys, db_lock).await)
+            }
+            Cmd::Unwatch => {
+                self.watches.clear();
+                Step::Reply(Ok(JsonVal::from("OK")))
+            }
+            cmd => match self.queue {
+                Some(_) if !cmd.batchable() => Step::Reply(Err(BAD_BATCH)),
+                Some(ref mut queue) => {
+                    queue.push(cmd);
+                    Step::Reply(Ok(JsonVal::from("QUEUED")))
+                }
+                None => Step::Eval(cmd),
+            },
+        }
+    }
+
+    /// Records the write sequence of each key, so `exec` can tell if they changed.
+    async fn watch(&mut self, keys: Vec<String>, db_lock: &Arc<RwLock<Database>>) -> Res<JsonVal> {
+        let seqs = blocking(db_lock, move |db_lock| {
+            let db = db_lock.read().unwrap();
+            let mut seqs = Vec::with_capacity(keys.len());
+            for key in keys {
+                let seq = db.watch_seq(&key);
+                seqs.push((key, seq));
+            }
+            Ok(seqs)
+        })
+        .await?;
+        self.watches.extend(seqs);
+        Ok(JsonVal::from("OK"))
+    }
+}
+
+impl Step {
+    /// Whether the step leaves the database unchanged, so it may run
+    /// alongside the other requests of its connection.
+    fn is_read(&self) -> bool {
+        match self {
+            Step::Eval(cmd) => cmd.is_read(),
+            _ => false,
+        }
+    }
+
+    /// Evaluates the step off the async workers.
+    async fn run(self, db_lock: &Arc<RwLock<Database>>) -> Res<JsonVal> {
+        match self {
+            Step::Reply(res) => res,
+            step => blocking(db_lock, move |db_lock| step.eval(db_lock)).await,
         }
+    }
+
+    fn eval(self, db_lock: &Arc<RwLock<Database>>) -> Res<JsonVal> {
+        match self {
+            Step::Reply(res) => res,
+            Step::Eval(cmd) => handle_request(cmd, db_lock),
+            Step::Exec(cmds, watches) => handle_exec(cmds, &watches, db_lock),
+        }
+    }
+}
+
+/// Runs `f` off the async workers, as it may wait on a lock or the disk.
+async fn blocking<T, F>(db_lock: &Arc<RwLock<Database>>, f: F) -> Res<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Arc<RwLock<Database>>) -> Res<T> + Send + 'static,
+{
+    let db_lock = db_lock.clone();
+    task::spawn_blocking(move || f(&db_lock)).await.unwrap_or(Err("request aborted"))
+}
+
+/// Evaluates a command, only taking the write lock for commands that mutate
+/// or load tables.
+fn handle_request(cmd: Cmd, db_lock: &Arc<RwLock<Database>>) -> Res<JsonVal> {
+    if let Cmd::Backup(dir) = cmd {
+        // the lock is only held while the keys are copied and the table files
+        // opened, not while the tables are read and written
+        let (dir, image) = {
+            let db = db_lock.read().unwrap();
+            (db.backup_path(&dir)?, db.backup_image()?)
+        };
+        return image.write(dir).map(JsonVal::from);
+    }
+    if let Cmd::Export(source, path, opts) = cmd {
+        // like a backup, the rows are copied under the lock and written after
+        let (path, rows) = {
+            let mut db = db_lock.write().unwrap();
+            (db.export_path(&path)?, db.export_rows(&source)?)
+        };
+        return export::write_file(path, &rows, &opts).map(JsonVal::from);
+    }
+    if let Cmd::Import(table, path, opts) = cmd {
+        // the file is read before taking the lock, so a big import only
+        // blocks other clients while its rows are inserted
+        let path = db_lock.read().unwrap().import_path(&path)?;
+        let (rows, report) = import::read_file(path, &opts)?;
+        if !rows.is_empty() {
+            let (_, ticket) = db_lock.write().unwrap().apply(Cmd::Insert(table, rows))?;
+            commit(ticket, db_lock)?;
+        }
+        return Ok(report.to_json());
+    }
+    if let Cmd::Query(qry) = cmd {
+        // cold tables are loaded under a brief write lock and scanned under
+        // the read lock, unless they were evicted again in between
+        qry.warm(&mut db_lock.write().unwrap())?;
+        if let Some(res) = qry.scan(&db_lock.read().unwrap()) {
+            return res.map(JsonVal::from);
+        }
+        return qry.exec(&mut db_lock.write().unwrap()).map(JsonVal::from);
+    }
+    if cmd.is_write() {
+        let (val, ticket) = db_lock.write().unwrap().apply(cmd)?;
+        commit(ticket, db_lock)?;
+        Ok(val)
+    } else {
+        let db = db_lock.read().unwrap();
+        eval_json_query(cmd, &db)
+    }
+}
+
+/// Applies a `multi` block, or replies `null` if a watched key has changed.
+fn handle_exec(cmds: Vec<Cmd>, watches: &[(String, u64)], db_lock: &Arc<RwLock<Database>>) -> Res<JsonVal> {
+    let (val, ticket) = {
+        let mut db = db_lock.write().unwrap();
+        if watches.iter().any(|(key, seq)| db.watch_seq(key) != *seq) {
+            return Ok(JsonVal::Null);
+        }
+        db.apply(Cmd::Batch(cmds))?
     };
+    commit(ticket, db_lock)?;
     Ok(val)
 }
 
+/// Waits until the write with `ticket` is committed. Writes applied while
+/// another commit runs are flushed, and synced if needed, as one batch.
+fn commit(ticket: u64, db_lock: &Arc<RwLock<Database>>) -> Res<()> {
+    let commits = db_lock.read().unwrap().commits();
+    commits
+        .wait(ticket, || db_lock.read().unwrap().commit_logs())
+        .map_err(|e| {
+            eprintln!("error committing logs; error = {:?}", e);
+            "cannot sync logs"
+        })
+}
+
 impl Response {
+    fn new(id: Option<JsonVal>, res: Res<JsonVal>) -> Self {
+        match res {
+            Ok(value) => Response::Value { id, value },
+            Err(msg) => {
+                eprintln!("error: {}", msg);
+                Response::Error { id, msg }
+            }
+        }
+    }
+
     fn serialize(&self) -> String {
         match self {
-            Response::Value { value: val, .. } => format!("{}", val),
+            Response::Value { id: None, value } => format!("{}", value),
+            Response::Value { id: Some(id), value } => {
+                format!("{}", JsonVal::from(obj! {"id" => id.clone(), "value" => value.clone()}))
+            }
+            Response::Error { id: None, msg } => format!("{}", JsonVal::from(obj! {"error" => *msg})),
+            Response::Error { id: Some(id), msg } => {
+                format!("{}", JsonVal::from(obj! {"id" => id.clone(), "error" => *msg}))
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+
+    use futures::stream;
+    use serde_json::json;
+
+    use super::*;
+
+    fn replies(lines: &[&str], db: &Arc<RwLock<Database>>) -> Vec<String> {
+        let lines: Vec<Result<String, ()>> = lines.iter().map(|line| Ok(line.to_string())).collect();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            serve(stream::iter(lines), tx, db.clone()).await;
+            rx.map(|response| response.serialize()).collect().await
+        })
+    }
+
+    #[test]
+    fn parse_request_ok() {
+        let (id, cmd) = parse_request("{\"get\": \"k\"}");
+        assert_eq!(id, None);
+        assert!(matches!(cmd, Ok(Cmd::Get(ref key)) if key == "k"));
+        let (id, cmd) = parse_request("{\"id\": 7, \"cmd\": {\"set\": [\"k\", 1]}}");
+        assert_eq!(id, Some(json!(7)));
+        assert!(matches!(cmd, Ok(Cmd::Set(ref key, _)) if key == "k"));
+        let (id, cmd) = parse_request("{\"id\": \"a\", \"cmd\": [{\"get\": \"k\"}]}");
+        assert_eq!(id, Some(json!("a")));
+        assert!(matches!(cmd, Ok(Cmd::Batch(ref cmds)) if cmds.len() == 1));
+        // the id is kept for the error reply
+        let (id, cmd) = parse_request("{\"id\": 8, \"cmd\": {\"get\": \"k\"}, \"x\": 1}");
+        assert_eq!(id, Some(json!(8)));
+        assert_eq!(cmd.err(), Some("bad request"));
+        let (id, cmd) = parse_request("{\"id\": 9}");
+        assert_eq!(id, Some(json!(9)));
+        assert_eq!(cmd.err(), Some("bad request"));
+        let (id, cmd) = parse_request("{\"id\": 1,");
+        assert_eq!(id, None);
+        assert_eq!(cmd.err(), Some("bad json"));
+    }
+
+    #[test]
+    fn response_ok() {
+        assert_eq!(Response::new(None, Ok(json!([1, 2]))).serialize(), "[1,2]");
+        assert_eq!(Response::new(Some(json!(1)), Ok(json!("a"))).serialize(), "{\"id\":1,\"value\":\"a\"}");
+        assert_eq!(Response::new(None, Err("bad command")).serialize(), "{\"error\":\"bad command\"}");
+        assert_eq!(
+            Response::new(Some(json!("x")), Err("bad command")).serialize(),
+            "{\"error\":\"bad command\",\"id\":\"x\"}"
+        );
+    }
+
+    #[test]
+    fn serve_ok() {
+        let db = Arc::new(RwLock::new(Database::open("./", "pipeline").unwrap()));
+        let got = replies(
+            &[
+                "{\"id\": 1, \"cmd\": {\"set\": [\"k\", 1]}}",
+                "{\"id\": 2, \"cmd\": {\"set\": [\"k\", 2]}}",
+                "{\"id\": 3, \"cmd\": {\"get\": \"k\"}}",
+                "{\"id\": 4, \"cmd\": {\"nope\": \"k\"}}",
+                "{\"id\": 5, \"cmd\": {\"set\": [\"k\", 3]}}",
+                "{\"get\": \"k\"}",
+            ],
+            &db,
+        );
+        // writes apply in order, and wait for the reads sent before them
+        assert_eq!(
+            got,
+            vec![
+                "{\"id\":1,\"value\":null}",
+                "{\"id\":2,\"value\":1}",
+                "{\"id\":3,\"value\":2}",
+                "{\"error\":\"bad command\",\"id\":4}",
+                "{\"id\":5,\"value\":2}",
+                "3",
+            ]
+        );
+
+        let got = replies(
+            &[
+                "{\"multi\": null}",
+                "{\"id\": 1, \"cmd\": {\"get\": \"k\"}}",
+                "{\"backup\": \"pipeline\"}",
+                "{\"exec\": null}",
+            ],
+            &db,
+        );
+        assert_eq!(
+            got,
+            vec![
+                "\"OK\"",
+                "{\"id\":1,\"value\":\"QUEUED\"}",
+                "{\"error\":\"backup, export, import and query cannot be run in a batch\"}",
+                "[3]",
+            ]
+        );
+
+        remove_file("./pipeline.db").unwrap();
+        remove_file("./pipeline.keys").unwrap();
+    }
+}
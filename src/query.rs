@@ -22,32 +22,104 @@ macro_rules! row(
 pub struct Query {
     selects: Vec<Expr>,
     from: String,
+    #[serde(default)]
+    time: Option<TimeRange>,
+}
+
+/// Keeps the rows whose `field` holds a time, in milliseconds since the unix
+/// epoch, from `from` up to but not including `to`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TimeRange {
+    pub field: String,
+    #[serde(default)]
+    pub from: Option<i64>,
+    #[serde(default)]
+    pub to: Option<i64>,
+}
+
+impl TimeRange {
+    fn contains(&self, row: &Row) -> bool {
+        match row.get(&self.field).and_then(JsonVal::as_f64) {
+            Some(time) => self.from.is_none_or(|from| time >= from as f64) && self.to.is_none_or(|to| time < to as f64),
+            None => false,
+        }
+    }
+
+    /// Whether any time from `start` up to `end` is in the range.
+    pub fn overlaps(&self, start: i64, end: i64) -> bool {
+        self.from.is_none_or(|from| from < end) && self.to.is_none_or(|to| to > start)
+    }
 }
 
 impl Query {
     pub fn from(selects: Vec<Expr>, from: String) -> Self {
-        Self { selects, from }
+        Self { selects, from, time: None }
     }
 
-    pub fn exec(&self, db: &Database) -> Res<Vec<Row>> {
-        let tbl = db.find_table(&self.from).ok_or("cannot find table")?;
-        let mut rows = eval_rows(&self.selects, tbl)?;
-        if rows.is_empty() {
-            let mut row = Map::new();
-            eval_aggregations(&self.selects, &mut row, tbl.rows())?;
-            rows.push(row);
-        } else {
-            eval_aggregations(&self.selects, &mut rows[0], tbl.rows())?;
+    /// Runs the query one table at a time; a partitioned table only reads
+    /// the partitions the time filter can match.
+    pub fn exec(&self, db: &mut Database) -> Res<Vec<Row>> {
+        let mut scan = Scan::default();
+        eval_aggregations(&self.selects, &mut scan.totals, &[])?;
+        for name in db.scan_tables(&self.from, self.time.as_ref()) {
+            db.warm_table(&name)?;
+            self.scan_table(db, &name, &mut scan)?;
         }
+        Ok(scan.finish())
+    }
 
-        Ok(rows)
+    /// Loads the tables the query reads if they were left on disk.
+    pub fn warm(&self, db: &mut Database) -> Res<()> {
+        for name in db.scan_tables(&self.from, self.time.as_ref()) {
+            db.warm_table(&name)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the query without loading anything, or returns `None` if a table
+    /// it reads is not in memory.
+    pub fn scan(&self, db: &Database) -> Option<Res<Vec<Row>>> {
+        let names = db.scan_tables(&self.from, self.time.as_ref());
+        if names.iter().any(|name| db.is_cold(name)) {
+            return None;
+        }
+        let mut scan = Scan::default();
+        let res = eval_aggregations(&self.selects, &mut scan.totals, &[])
+            .and_then(|_| names.iter().try_for_each(|name| self.scan_table(db, name, &mut scan)));
+        Some(res.map(|_| scan.finish()))
+    }
+
+    fn scan_table(&self, db: &Database, name: &str, scan: &mut Scan) -> Res<()> {
+        let tbl = db.find_table(name).ok_or("cannot find table")?;
+        let matched: Vec<&Row> = tbl.rows().iter().filter(|row| self.time.as_ref().is_none_or(|time| time.contains(row))).collect();
+        scan.rows.extend(eval_rows(&self.selects, &matched)?);
+        eval_aggregations(&self.selects, &mut scan.totals, &matched)
     }
 }
 
-fn eval_aggregations(selects: &[Expr], out: &mut Row, rows: &[Row]) -> Res<()> {
+/// The rows and running totals of a query so far.
+#[derive(Default)]
+struct Scan {
+    rows: Vec<Row>,
+    totals: Row,
+}
+
+impl Scan {
+    fn finish(mut self) -> Vec<Row> {
+        if self.rows.is_empty() {
+            self.rows.push(self.totals);
+        } else {
+            self.rows[0].extend(self.totals);
+        }
+        self.rows
+    }
+}
+
+/// Adds the rows to the running totals in `out`.
+fn eval_aggregations(selects: &[Expr], out: &mut Row, rows: &[&Row]) -> Res<()> {
     for select in selects {
         match select {
-            Expr::Sum(box Expr::Get(key)) => { let mut total = 0.0;
+            Expr::Sum(box Expr::Get(key)) => { let mut total = out.get(&select.to_string()).and_then(JsonVal::as_f64).unwrap_or(0.0);
                for row in rows {
                    if let Some(JsonVal::Number(val)) = row.get(key) {
                        if let Some(val) = val.as_f64() {
@@ -63,9 +135,9 @@ fn eval_aggregations(selects: &[Expr], out: &mut Row, rows: &[Row]) -> Res<()> {
     Ok(())
 }
 
-fn eval_rows(selects: &[Expr], tbl: &Table) -> Res<Vec<Row>> {
+fn eval_rows(selects: &[Expr], tbl: &[&Row]) -> Res<Vec<Row>> {
     let mut rows = Vec::new();
-    for row in tbl.rows() {
+    for row in tbl {
         let row = eval_row(selects, row)?;
         if !row.is_empty() {
             rows.push(row);
@@ -227,11 +299,13 @@ mod tests {
         db.eval_cmd(cmd).unwrap();
         let expr = Expr::Sum(Box::new(Expr::Get("price".to_string())));
         let qry = Query::from(vec![expr], "p".to_string());
-        let res = qry.exec(&db).unwrap();
+        let res = qry.exec(&mut db).unwrap();
         assert_eq!(res.len(), 1);
         assert_eq!(res[0], obj! {"sum(price)" => 6.0});
 
         remove_file("./t5.db").unwrap();
+
+        remove_file("./t5.keys").unwrap();
         remove_file("./p.table").unwrap();
     }
 
@@ -246,13 +320,15 @@ mod tests {
         db.eval_cmd(cmd).unwrap();
         let expr = Expr::Get("price".to_string());
         let qry = Query::from(vec![expr], "prices".to_string());
-        let res = qry.exec(&db).unwrap();
+        let res = qry.exec(&mut db).unwrap();
         assert_eq!(res.len(), 3);
         assert_eq!(res[0], obj! {"price" => 1});
         assert_eq!(res[1], obj! {"price" => 2});
         assert_eq!(res[2], obj! {"price" => 3});
 
         remove_file("./t6.db").unwrap();
+
+        remove_file("./t6.keys").unwrap();
         remove_file("./prices.table").unwrap();
     }
 }